@@ -1,96 +1,649 @@
 use arrow_flight::flight_service_client::FlightServiceClient;
 use arrow_flight::flight_service_server::FlightServiceServer;
 use async_trait::async_trait;
+use datafusion::arrow::array::{AsArray, RecordBatch};
+use datafusion::arrow::datatypes::{Fields, Schema, SchemaRef, UInt64Type};
 use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::ipc::writer::StreamWriter;
+use datafusion::arrow::json::writer::{JsonArray, WriterBuilder};
 use datafusion::arrow::util::display::{ArrayFormatter, FormatOptions};
+use datafusion::common::stats::Precision;
 use datafusion::error::DataFusionError;
-use datafusion::execution::SessionStateBuilder;
-use datafusion::physical_plan::{execute_stream, ExecutionPlan};
+use datafusion::execution::disk_manager::DiskManagerBuilder;
+use datafusion::execution::memory_pool::{
+    FairSpillPool, MemoryConsumer, MemoryLimit, MemoryPool, MemoryReservation,
+};
+use datafusion::execution::runtime_env::{RuntimeEnv, RuntimeEnvBuilder};
+use datafusion::execution::{SendableRecordBatchStream, SessionState, SessionStateBuilder};
+use datafusion::logical_expr::LogicalPlan;
+use datafusion::physical_optimizer::PhysicalOptimizerRule;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{displayable, execute_stream, ExecutionPlan};
 use datafusion::prelude::{ParquetReadOptions, SessionConfig, SessionContext};
+use datafusion::sql::parser::DFParserBuilder;
+use datafusion::sql::sqlparser::dialect::GenericDialect;
+use datafusion::sql::sqlparser::tokenizer::{Location, Token};
 use datafusion_distributed::{
-    display_plan_ascii, ArrowFlightEndpoint, BoxCloneSyncChannel, ChannelResolver, DistributedExt,
-    DistributedPhysicalOptimizerRule, DistributedSessionBuilderContext,
+    display_plan_ascii, rewrite_distributed_plan_with_metrics, ArrowFlightEndpoint,
+    BoxCloneSyncChannel, ChannelResolver, DistributedExt, DistributedMetricsFormat,
+    DistributedPhysicalOptimizerRule, DistributedSessionBuilderContext, NetworkBoundaryExt, Stage,
 };
-use futures::TryStreamExt;
+use futures::future::BoxFuture;
+use futures::{StreamExt, TryStreamExt};
+use http_body::{Frame, SizeHint};
 use hyper_util::rt::TokioIo;
+use remote::RemoteChannelResolver;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
 use std::env::current_dir;
 use std::fmt::Display;
 use std::fs;
-use std::sync::{Arc, LazyLock};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tabled::builder::Builder;
+use tabled::settings::Style;
+use tabled::Table;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tonic::transport::{Endpoint, Server};
 use url::Url;
 use vercel_runtime::{run, Body, Error, Request, RequestPayloadExt, Response, StatusCode};
 
-const MAX_RESULTS: usize = 500;
+const DEFAULT_ROW_LIMIT: usize = 500;
+const MAX_ROW_LIMIT: usize = 10_000;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TIMEOUT: Duration = Duration::from_secs(25);
+
+const DEFAULT_MEMORY_LIMIT_MB: usize = 256;
+const MAX_MEMORY_LIMIT_MB: usize = 1024;
+
+const DEFAULT_WORKERS: usize = 16;
+const MAX_WORKERS: usize = 64;
 
-const DUMMY_URL: &str = "http://localhost:50051";
+/// Memory all the simulated workers together can reserve before spilling, shared by all the
+/// requests they serve.
+const WORKERS_MEMORY_LIMIT_MB: usize = 256;
 
+const MAX_NETWORK_LATENCY: Duration = Duration::from_secs(1);
+const MAX_SLOWDOWN: f64 = 100.0;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Rows each run of a verified statement may return, as all of them are kept to be compared.
+const MAX_VERIFY_ROWS: usize = MAX_ROW_LIMIT;
+
+/// A simulated worker, serving its own Flight endpoint over in-memory connections.
 #[derive(Clone)]
-struct InMemoryChannelResolver {
-    channel: BoxCloneSyncChannel,
+struct InMemoryWorker {
+    url: Url,
+    /// Kept across restarts of the endpoint, so disk managers are neither shared with other
+    /// workers nor lost when the endpoint comes back. The memory pool is the one all workers
+    /// share, bounded by `WORKERS_MEMORY_LIMIT_MB`.
+    runtime_env: Arc<RuntimeEnv>,
+    endpoint: Arc<Mutex<Option<WorkerEndpoint>>>,
+    restarts: Arc<AtomicUsize>,
+    /// Created on first use and shared by all the clients of the worker, so they reuse its
+    /// connection.
+    channel: Arc<OnceLock<BoxCloneSyncChannel>>,
 }
 
-impl InMemoryChannelResolver {
-    fn new() -> Self {
-        let (client, server) = tokio::io::duplex(1024 * 1024);
+/// A running endpoint task, and where to send it the server side of new connections.
+struct WorkerEndpoint {
+    connections: mpsc::UnboundedSender<DuplexStream>,
+    task: JoinHandle<()>,
+}
 
-        let mut client = Some(client);
-        let channel = Endpoint::try_from(DUMMY_URL)
-            .expect("Invalid dummy URL for building an endpoint. This should never happen")
-            .connect_with_connector_lazy(tower::service_fn(move |_| {
-                let client = client
-                    .take()
-                    .expect("Client taken twice. This should never happen");
-                async move { Ok::<_, std::io::Error>(TokioIo::new(client)) }
-            }));
-
-        let this = Self {
-            channel: BoxCloneSyncChannel::new(channel),
-        };
-        let this_clone = this.clone();
-
-        let endpoint =
-            ArrowFlightEndpoint::try_new(move |ctx: DistributedSessionBuilderContext| {
-                let this = this.clone();
-                async move {
-                    let builder = SessionStateBuilder::new()
-                        .with_default_features()
-                        .with_distributed_channel_resolver(this)
-                        .with_runtime_env(ctx.runtime_env.clone());
-                    Ok(builder.build())
-                }
+impl InMemoryWorker {
+    fn new(url: Url, memory_pool: Arc<dyn MemoryPool>) -> Self {
+        Self {
+            url,
+            runtime_env: RuntimeEnvBuilder::new()
+                .with_memory_pool(memory_pool)
+                .with_disk_manager_builder(DiskManagerBuilder::default())
+                .build_arc()
+                .expect("Cannot build the runtime of a simulated worker. This should never happen"),
+            endpoint: Arc::default(),
+            restarts: Arc::default(),
+            channel: Arc::default(),
+        }
+    }
+
+    /// The channel to the worker. It connects lazily, and opens a fresh in-memory connection
+    /// whenever it has to reconnect, such as after the endpoint died.
+    fn channel(&self) -> BoxCloneSyncChannel {
+        self.channel
+            .get_or_init(|| {
+                let worker = self.clone();
+                let channel = Endpoint::try_from(self.url.to_string())
+                    .expect("Invalid worker URL for building an endpoint. This should never happen")
+                    .connect_with_connector_lazy(tower::service_fn(move |_| {
+                        let connection = worker.connect();
+                        async move { connection.map(TokioIo::new) }
+                    }));
+                BoxCloneSyncChannel::new(channel)
             })
-            .unwrap();
+            .clone()
+    }
+
+    fn started(&self) -> bool {
+        self.endpoint.lock().unwrap().is_some()
+    }
+
+    /// Hands the server side of a new duplex pair to the endpoint, starting it first if it never
+    /// ran or if its task died, and returns the client side.
+    fn connect(&self) -> Result<DuplexStream, std::io::Error> {
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let mut endpoint = self.endpoint.lock().unwrap();
+        let server = match endpoint.as_ref() {
+            Some(running) if !running.task.is_finished() => {
+                match running.connections.send(server) {
+                    Ok(()) => return Ok(client),
+                    Err(mpsc::error::SendError(server)) => server,
+                }
+            }
+            _ => server,
+        };
+        if endpoint.is_some() {
+            self.restarts.fetch_add(1, Ordering::Relaxed);
+        }
+        let restarted = self.spawn_endpoint()?;
+        restarted
+            .connections
+            .send(server)
+            .map_err(|_| std::io::Error::other("Simulated worker stopped right after starting"))?;
+        *endpoint = Some(restarted);
+        Ok(client)
+    }
+
+    fn spawn_endpoint(&self) -> Result<WorkerEndpoint, std::io::Error> {
+        let runtime_env = self.runtime_env.clone();
+        let endpoint = ArrowFlightEndpoint::try_new(move |_: DistributedSessionBuilderContext| {
+            let runtime_env = runtime_env.clone();
+            async move {
+                let builder = SessionStateBuilder::new()
+                    .with_default_features()
+                    .with_distributed_channel_resolver(CHANNEL_RESOLVER.clone())
+                    .with_runtime_env(runtime_env);
+                Ok(builder.build())
+            }
+        })
+        .map_err(std::io::Error::other)?;
 
-        tokio::spawn(async move {
-            Server::builder()
+        let (connections, incoming) = mpsc::unbounded_channel();
+        let incoming = UnboundedReceiverStream::new(incoming).map(Ok::<_, std::io::Error>);
+        let url = self.url.clone();
+        let task = tokio::spawn(async move {
+            let result = Server::builder()
                 .add_service(FlightServiceServer::new(endpoint))
-                .serve_with_incoming(tokio_stream::once(Ok::<_, std::io::Error>(server)))
-                .await
+                .serve_with_incoming(incoming)
+                .await;
+            if let Err(err) = result {
+                eprintln!("error: simulated worker {url} stopped: {err}");
+            }
         });
+        Ok(WorkerEndpoint { connections, task })
+    }
+
+    /// Whether the endpoint answers a Flight call. Endpoints only implement the calls they need,
+    /// so an `Unimplemented` error is a valid answer.
+    async fn health(&self) -> WorkerHealth {
+        let error = probe_worker(FlightServiceClient::new(self.channel())).await;
+        WorkerHealth {
+            url: self.url.to_string(),
+            healthy: error.is_none(),
+            error,
+            restarts: Some(self.restarts.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Calls the worker and returns why it did not answer, if it did not.
+async fn probe_worker(mut client: FlightServiceClient<BoxCloneSyncChannel>) -> Option<String> {
+    let response = tokio::time::timeout(
+        HEALTH_CHECK_TIMEOUT,
+        client.list_actions(arrow_flight::Empty {}),
+    )
+    .await;
+    match response {
+        Ok(Ok(_)) => None,
+        Ok(Err(status)) if status.code() == tonic::Code::Unimplemented => None,
+        Ok(Err(status)) => Some(status.to_string()),
+        Err(_) => Some(format!("No answer after {HEALTH_CHECK_TIMEOUT:?}")),
+    }
+}
+
+/// Probes the workers running as separate processes.
+async fn remote_health(resolver: &RemoteChannelResolver) -> Result<Vec<WorkerHealth>, Error> {
+    let probes = resolver.get_urls()?.into_iter().map(|url| async move {
+        let error = match resolver.get_flight_client_for_url(&url).await {
+            Ok(client) => probe_worker(client).await,
+            Err(err) => Some(err.to_string()),
+        };
+        WorkerHealth {
+            url: url.to_string(),
+            healthy: error.is_none(),
+            error,
+            restarts: None,
+        }
+    });
+    Ok(futures::future::join_all(probes).await)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct WorkerHealth {
+    url: String,
+    healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Times the endpoint was started again after dying. Only known for simulated workers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restarts: Option<usize>,
+}
+
+#[derive(Clone)]
+struct InMemoryChannelResolver {
+    workers: Arc<Vec<InMemoryWorker>>,
+    /// How many of the workers are advertised to the distributed planner.
+    active: usize,
+    network: NetworkConditions,
+    faults: Vec<WorkerFault>,
+    stragglers: Vec<Straggler>,
+}
 
-        this_clone
+impl InMemoryChannelResolver {
+    /// Creates `workers` simulated workers reachable at `http://worker-0`..`http://worker-N`. Their
+    /// endpoints start on their first connection.
+    fn new(workers: usize) -> Self {
+        let memory_pool: Arc<dyn MemoryPool> =
+            Arc::new(FairSpillPool::new(WORKERS_MEMORY_LIMIT_MB * 1024 * 1024));
+        let workers = (0..workers)
+            .map(|i| {
+                InMemoryWorker::new(
+                    Url::parse(&format!("http://worker-{i}"))
+                        .expect("Invalid worker URL. This should never happen"),
+                    memory_pool.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        Self {
+            active: workers.len().min(DEFAULT_WORKERS),
+            workers: Arc::new(workers),
+            network: NetworkConditions::default(),
+            faults: vec![],
+            stragglers: vec![],
+        }
+    }
+
+    /// Checks the advertised workers and any other that was started, restarting the ones whose
+    /// endpoint died. Workers that never ran are left alone, so checks do not start them all.
+    async fn health(&self) -> Vec<WorkerHealth> {
+        let workers = self
+            .workers
+            .iter()
+            .enumerate()
+            .filter(|(i, worker)| *i < self.active || worker.started())
+            .map(|(_, worker)| worker.health());
+        futures::future::join_all(workers).await
+    }
+
+    /// Returns a resolver sharing the same workers that only advertises the first `workers`.
+    fn with_workers(&self, workers: usize) -> Self {
+        Self {
+            workers: self.workers.clone(),
+            active: workers.min(self.workers.len()),
+            network: self.network,
+            faults: self.faults.clone(),
+            stragglers: self.stragglers.clone(),
+        }
+    }
+
+    fn with_network(self, network: NetworkConditions) -> Self {
+        Self { network, ..self }
+    }
+
+    fn with_faults(self, faults: Vec<WorkerFault>) -> Self {
+        Self { faults, ..self }
+    }
+
+    fn with_stragglers(self, stragglers: Vec<Straggler>) -> Self {
+        Self { stragglers, ..self }
     }
 }
 
 #[async_trait]
 impl ChannelResolver for InMemoryChannelResolver {
     fn get_urls(&self) -> Result<Vec<Url>, DataFusionError> {
-        Ok(vec![Url::parse(DUMMY_URL).unwrap(); 16])
+        Ok(self.workers[..self.active]
+            .iter()
+            .enumerate()
+            .map(|(i, worker)| {
+                let mut url = self.network.encode_in(worker.url.clone());
+                if let Some(fault) = self.faults.iter().find(|fault| fault.worker == i) {
+                    url.query_pairs_mut()
+                        .append_pair("fault", fault.fault.as_str());
+                }
+                if let Some(straggler) = self.stragglers.iter().find(|s| s.worker == i) {
+                    url.query_pairs_mut()
+                        .append_pair("slowdown", &straggler.slowdown.to_string());
+                }
+                url
+            })
+            .collect())
     }
 
     async fn get_flight_client_for_url(
         &self,
-        _: &Url,
+        url: &Url,
     ) -> Result<FlightServiceClient<BoxCloneSyncChannel>, DataFusionError> {
-        Ok(FlightServiceClient::new(self.channel.clone()))
+        let worker = self
+            .workers
+            .iter()
+            .find(|worker| worker.url.host_str() == url.host_str())
+            .ok_or_else(|| DataFusionError::Internal(format!("No simulated worker at {url}")))?;
+        let network = NetworkConditions::decode_from(url);
+        let fault = url
+            .query_pairs()
+            .find(|(key, _)| key == "fault")
+            .and_then(|(_, value)| Fault::parse(&value));
+        let slowdown = url
+            .query_pairs()
+            .find(|(key, _)| key == "slowdown")
+            .and_then(|(_, value)| value.parse().ok());
+        if network == NetworkConditions::default() && fault.is_none() && slowdown.is_none() {
+            return Ok(FlightServiceClient::new(worker.channel()));
+        }
+        Ok(FlightServiceClient::new(BoxCloneSyncChannel::new(
+            ShapedChannel {
+                inner: worker.channel(),
+                network,
+                fault,
+                slowdown,
+            },
+        )))
+    }
+}
+
+/// Latency and bandwidth of the simulated links between workers. They travel in the query string
+/// of the worker URLs, so the tasks that workers run against each other are shaped too.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct NetworkConditions {
+    /// Added once per call, before the response starts.
+    latency: Duration,
+    /// In bytes per second, unlimited if absent.
+    bandwidth: Option<u64>,
+}
+
+impl NetworkConditions {
+    fn encode_in(&self, mut url: Url) -> Url {
+        if !self.latency.is_zero() {
+            url.query_pairs_mut()
+                .append_pair("latency_ms", &self.latency.as_millis().to_string());
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            url.query_pairs_mut()
+                .append_pair("bandwidth_bps", &bandwidth.to_string());
+        }
+        url
+    }
+
+    fn decode_from(url: &Url) -> Self {
+        let mut network = Self::default();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "latency_ms" => network.latency = Duration::from_millis(value.parse().unwrap_or(0)),
+                "bandwidth_bps" => network.bandwidth = value.parse().ok(),
+                _ => {}
+            }
+        }
+        network
+    }
+
+    fn transfer_time(&self, bytes: usize) -> Duration {
+        self.bandwidth.map_or(Duration::ZERO, |bandwidth| {
+            Duration::from_secs_f64(bytes as f64 / bandwidth.max(1) as f64)
+        })
+    }
+}
+
+/// Failure injected in every call to a simulated worker.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Fault {
+    /// Calls fail right away as if the worker was unreachable.
+    Fail,
+    /// Calls never get a response.
+    Hang,
+    /// Responses are cut with an error after their first message.
+    Drop,
+}
+
+impl Fault {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fail => "fail",
+            Self::Hang => "hang",
+            Self::Drop => "drop",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [Self::Fail, Self::Hang, Self::Drop]
+            .into_iter()
+            .find(|fault| fault.as_str() == value)
+    }
+}
+
+/// A simulated worker serving the tasks it runs `slowdown` times slower than it could.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Straggler {
+    /// Index of the simulated worker, the N in `worker-N`.
+    worker: usize,
+    slowdown: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct WorkerFault {
+    /// Index of the simulated worker, the N in `worker-N`.
+    worker: usize,
+    fault: Fault,
+}
+
+/// Channel delaying the responses of another one according to some [NetworkConditions], and
+/// injecting a [Fault] in them if any. Like network conditions, faults travel in the worker URLs.
+#[derive(Clone)]
+struct ShapedChannel {
+    inner: BoxCloneSyncChannel,
+    network: NetworkConditions,
+    fault: Option<Fault>,
+    /// Makes the worker look this many times slower, see [Straggler].
+    slowdown: Option<f64>,
+}
+
+impl tower::Service<http::Request<tonic::body::Body>> for ShapedChannel {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = <BoxCloneSyncChannel as tower::Service<http::Request<tonic::body::Body>>>::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<tonic::body::Body>) -> Self::Future {
+        let network = self.network;
+        let fault = self.fault;
+        if fault == Some(Fault::Fail) {
+            return Box::pin(async move {
+                tokio::time::sleep(network.latency).await;
+                Ok(tonic::Status::unavailable("Simulated worker failure").into_http())
+            });
+        }
+        let slowdown = self.slowdown;
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            if fault == Some(Fault::Hang) {
+                futures::future::pending::<()>().await;
+            }
+            let start = Instant::now();
+            let response = response.await?;
+            if let Some(slowdown) = slowdown {
+                tokio::time::sleep(start.elapsed().mul_f64(slowdown - 1.0)).await;
+            }
+            tokio::time::sleep(network.latency).await;
+            Ok(response.map(|body| {
+                tonic::body::Body::new(ShapedBody {
+                    inner: body,
+                    network,
+                    slowdown,
+                    waiting_since: None,
+                    pause: None,
+                    drop_stream: fault == Some(Fault::Drop),
+                    data_frames: 0,
+                })
+            }))
+        })
+    }
+}
+
+/// Response body that, after yielding each data frame, waits for as long as the frame would take
+/// to go through the simulated link, plus the extra time a straggler would have spent producing it.
+struct ShapedBody {
+    inner: tonic::body::Body,
+    network: NetworkConditions,
+    slowdown: Option<f64>,
+    /// When the body started waiting for the frame being produced.
+    waiting_since: Option<Instant>,
+    pause: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// Whether to cut the stream once some data went through.
+    drop_stream: bool,
+    data_frames: usize,
+}
+
+impl http_body::Body for ShapedBody {
+    type Data = <tonic::body::Body as http_body::Body>::Data;
+    type Error = tonic::Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(pause) = &mut this.pause {
+            ready!(pause.as_mut().poll(cx));
+            this.pause = None;
+        }
+        if this.drop_stream && this.data_frames > 0 {
+            this.drop_stream = false;
+            this.inner = tonic::body::Body::empty();
+            return Poll::Ready(Some(Err(tonic::Status::aborted(
+                "Simulated worker dropped the stream",
+            ))));
+        }
+        let waiting_since = *this.waiting_since.get_or_insert_with(Instant::now);
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        this.waiting_since = None;
+        if let Some(data) = frame.as_ref().and_then(|f| f.as_ref().ok()?.data_ref()) {
+            this.data_frames += 1;
+            let mut pause = this.network.transfer_time(data.len());
+            if let Some(slowdown) = this.slowdown {
+                pause += waiting_since.elapsed().mul_f64(slowdown - 1.0);
+            }
+            if !pause.is_zero() {
+                this.pause = Some(Box::pin(tokio::time::sleep(pause)));
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.pause.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// [FairSpillPool] that also remembers the highest amount of memory reserved at once.
+#[derive(Debug)]
+struct PeakMemoryPool {
+    inner: FairSpillPool,
+    peak: AtomicUsize,
+}
+
+impl PeakMemoryPool {
+    fn new(limit: usize) -> Self {
+        Self {
+            inner: FairSpillPool::new(limit),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    fn reset_peak(&self) {
+        self.peak.store(self.inner.reserved(), Ordering::Relaxed);
+    }
+
+    fn update_peak(&self) {
+        self.peak
+            .fetch_max(self.inner.reserved(), Ordering::Relaxed);
+    }
+}
+
+impl MemoryPool for PeakMemoryPool {
+    fn register(&self, consumer: &MemoryConsumer) {
+        self.inner.register(consumer)
+    }
+
+    fn unregister(&self, consumer: &MemoryConsumer) {
+        self.inner.unregister(consumer)
+    }
+
+    fn grow(&self, reservation: &MemoryReservation, additional: usize) {
+        self.inner.grow(reservation, additional);
+        self.update_peak();
+    }
+
+    fn shrink(&self, reservation: &MemoryReservation, shrink: usize) {
+        self.inner.shrink(reservation, shrink)
+    }
+
+    fn try_grow(
+        &self,
+        reservation: &MemoryReservation,
+        additional: usize,
+    ) -> datafusion::error::Result<()> {
+        self.inner.try_grow(reservation, additional)?;
+        self.update_peak();
+        Ok(())
+    }
+
+    fn reserved(&self) -> usize {
+        self.inner.reserved()
+    }
+
+    fn memory_limit(&self) -> MemoryLimit {
+        self.inner.memory_limit()
     }
 }
 
 static CHANNEL_RESOLVER: LazyLock<InMemoryChannelResolver> =
-    LazyLock::new(InMemoryChannelResolver::new);
+    LazyLock::new(|| InMemoryChannelResolver::new(MAX_WORKERS));
+
+/// Workers running as separate processes, see `worker/main.rs`. When configured, they replace the
+/// simulated ones.
+static REMOTE_CHANNEL_RESOLVER: LazyLock<Result<Option<RemoteChannelResolver>, String>> =
+    LazyLock::new(|| RemoteChannelResolver::from_env().map_err(|err| err.to_string()));
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -98,195 +651,2477 @@ async fn main() -> Result<(), Error> {
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
 struct SqlRequest {
     stmts: Vec<String>,
+    /// Whole SQL script, split into statements on the server. Mutually exclusive with `stmts`.
+    sql: Option<String>,
+    /// Forces the distributed planner on or off. When absent, it is only enabled if some
+    /// statement mentions `distributed.`, which keeps old share links working.
+    distributed: Option<bool>,
+    /// Number of simulated workers the distributed planner can spread tasks across.
+    workers: Option<usize>,
+    /// Latency added to every call between simulated workers.
+    network_latency_ms: Option<u64>,
+    /// Bandwidth of the links between simulated workers, in megabits per second. Unlimited if
+    /// absent.
+    network_bandwidth_mbps: Option<f64>,
+    /// Faults to inject in the calls made to some of the simulated workers, at most one per worker.
+    faults: Vec<WorkerFault>,
+    /// Simulated workers that run slower than the others, each listed at most once.
+    stragglers: Vec<Straggler>,
+    /// Overrides the `distributed.files_per_task` setting.
+    files_per_task: Option<usize>,
+    /// Overrides the `distributed.cardinality_task_count_factor` setting.
+    cardinality_task_count_factor: Option<f64>,
+    /// Also returns every cell as a typed JSON value in `SqlResult.values`.
+    typed_values: bool,
+    /// Response format. Takes precedence over the `Accept` header.
+    format: Option<OutputFormat>,
+    /// Maximum number of rows returned per statement, from 1 to `MAX_ROW_LIMIT`. Acts as the page
+    /// size when paginating with `offset`.
+    limit: Option<usize>,
+    /// Number of rows of the last statement to skip on the server. Pages are only stable across
+    /// requests if the last statement has an ORDER BY.
+    offset: usize,
+    /// Wall-clock time budget for running all the statements, at most `MAX_TIMEOUT`.
+    timeout_ms: Option<u64>,
+    /// Memory available to the session before operators start spilling to disk, from 1 to
+    /// `MAX_MEMORY_LIMIT_MB`.
+    memory_limit_mb: Option<usize>,
+    /// Also returns the physical plan annotated with the metrics collected while executing it,
+    /// like EXPLAIN ANALYZE does.
+    analyze: bool,
+    /// Also returns the logical and physical plans as JSON trees.
+    plan_trees: bool,
+    /// Also returns the plans, and the stage graph of distributed ones, rendered as diagrams.
+    diagram: Option<DiagramFormat>,
+    /// Also runs the last statement with the distributed planner toggled, and compares the rows
+    /// both runs return. Only reported in JSON responses.
+    verify: bool,
+}
+
+impl SqlRequest {
+    fn is_distributed(&self) -> bool {
+        self.distributed.unwrap_or_else(|| {
+            self.stmts
+                .iter()
+                .chain(&self.sql)
+                .any(|v| v.contains("distributed."))
+        })
+    }
+
+    /// Statements to run, either the ones in `stmts` or the ones `sql` splits into.
+    fn statements(&self) -> Result<Vec<SqlStatement>, SqlError> {
+        match &self.sql {
+            Some(_) if !self.stmts.is_empty() => Err(DataFusionError::Configuration(
+                "only one of sql and stmts can be provided".to_string(),
+            )
+            .into()),
+            Some(sql) => split_statements(sql),
+            None => Ok(self
+                .stmts
+                .iter()
+                .map(|sql| SqlStatement {
+                    sql: sql.clone(),
+                    source: None,
+                })
+                .collect()),
+        }
+    }
+
+    /// Validated in [build_context], as it is read by every statement.
+    fn row_limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_ROW_LIMIT)
+    }
+
+    fn memory_limit(&self) -> Result<usize, DataFusionError> {
+        let mb = self.memory_limit_mb.unwrap_or(DEFAULT_MEMORY_LIMIT_MB);
+        if !(1..=MAX_MEMORY_LIMIT_MB).contains(&mb) {
+            return Err(DataFusionError::Configuration(format!(
+                "memory_limit_mb must be between 1 and {MAX_MEMORY_LIMIT_MB}, got {mb}"
+            )));
+        }
+        Ok(mb * 1024 * 1024)
+    }
+
+    fn network(&self) -> Result<NetworkConditions, DataFusionError> {
+        let latency = Duration::from_millis(self.network_latency_ms.unwrap_or(0));
+        if latency > MAX_NETWORK_LATENCY {
+            return Err(DataFusionError::Configuration(format!(
+                "network_latency_ms must be at most {}, got {}",
+                MAX_NETWORK_LATENCY.as_millis(),
+                latency.as_millis()
+            )));
+        }
+        let bandwidth = match self.network_bandwidth_mbps {
+            Some(mbps) if mbps.is_finite() && mbps > 0.0 => Some((mbps * 125_000.0) as u64),
+            Some(mbps) => {
+                return Err(DataFusionError::Configuration(format!(
+                    "network_bandwidth_mbps must be positive, got {mbps}"
+                )))
+            }
+            None => None,
+        };
+        Ok(NetworkConditions { latency, bandwidth })
+    }
+
+    fn deadline(&self) -> Result<Deadline, DataFusionError> {
+        let timeout = self
+            .timeout_ms
+            .map_or(DEFAULT_TIMEOUT, Duration::from_millis);
+        if timeout.is_zero() || timeout > MAX_TIMEOUT {
+            return Err(DataFusionError::Configuration(format!(
+                "timeout_ms must be between 1 and {}, got {}",
+                MAX_TIMEOUT.as_millis(),
+                timeout.as_millis()
+            )));
+        }
+        Ok(Deadline {
+            at: tokio::time::Instant::now() + timeout,
+            timeout,
+        })
+    }
 }
 
 pub async fn handler(req: Request) -> Result<Response<Body>, Error> {
-    let req = match req.payload::<SqlRequest>()? {
-        Some(req) => req,
-        None => return throw_error("No sql request was passed", None, StatusCode::BAD_REQUEST),
+    let accepted_format = req
+        .headers()
+        .get_all("Accept")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find_map(OutputFormat::from_accept);
+
+    if req.method() == "GET" {
+        return health_response().await;
+    }
+
+    let req = match req.payload::<SqlRequest>() {
+        Ok(Some(req)) => req,
+        Ok(None) => return throw_error("No sql request was passed", None, StatusCode::BAD_REQUEST),
+        Err(err) => {
+            return throw_error(
+                &err.to_string(),
+                Some(Box::new(err)),
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+    let format = req.format.or(accepted_format).unwrap_or_default();
+
+    let res = match format {
+        OutputFormat::Arrow => execute_statements_arrow(req, "api/parquet")
+            .await
+            .map(|body| (vec![], Body::from(body))),
+        _ => execute_statements(req, "api/parquet")
+            .await
+            .map(|res| (format.page_headers(&res), format.render(res).into())),
+    };
+
+    match res {
+        Ok((headers, body)) => ok_response(format.content_type(), headers, body),
+        Err(err) => {
+            let status_code = err.status_code();
+            throw_sql_error(err, status_code)
+        }
+    }
+}
+
+/// Reports whether the workers queries run on are up, with a 503 status if any is not. Those are
+/// the remote workers when configured, the simulated ones otherwise.
+async fn health_response() -> Result<Response<Body>, Error> {
+    let (status_code, body) = match REMOTE_CHANNEL_RESOLVER.as_ref() {
+        Ok(Some(resolver)) => {
+            let workers = remote_health(resolver).await?;
+            (
+                workers_status_code(&workers),
+                json!({ "resolver": "remote", "workers": workers }),
+            )
+        }
+        Ok(None) => {
+            let workers = CHANNEL_RESOLVER.health().await;
+            (
+                workers_status_code(&workers),
+                json!({ "resolver": "simulated", "workers": workers }),
+            )
+        }
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({ "resolver": "remote", "error": err }),
+        ),
     };
+    Ok(Response::builder()
+        .status(status_code)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(body.to_string().into())?)
+}
+
+fn workers_status_code(workers: &[WorkerHealth]) -> StatusCode {
+    if workers.iter().all(|worker| worker.healthy) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+fn ok_response(
+    content_type: &str,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+) -> Result<Response<Body>, Error> {
+    let mut builder = Response::builder().status(StatusCode::OK);
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+    Ok(builder
+        .header(
+            "Cache-Control",
+            format!(
+                "public, max-age=0, must-revalidate, s-maxage={s_maxage}",
+                s_maxage = 60 * 60
+            ),
+        )
+        .header("Content-Type", content_type)
+        .body(body)?)
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// All the statement results as a `SqlResponse`.
+    #[default]
+    Json,
+    /// The record batches of the last statement as an Arrow IPC stream. Like in JSON, at most
+    /// `limit` rows are returned, up to `MAX_ROW_LIMIT`. The schema metadata tells whether rows
+    /// were left out under `truncated`, along with `total_rows` when known, `offset` and `limit`.
+    Arrow,
+    /// The last statement result as comma separated values.
+    Csv,
+    /// The last statement result as tab separated values.
+    Tsv,
+    /// The last statement result as a Markdown table.
+    Markdown,
+    /// The last statement result as an ASCII table.
+    Ascii,
+}
+
+impl OutputFormat {
+    /// Picks the first media type in an `Accept` header value that maps to a format.
+    fn from_accept(accept: &str) -> Option<Self> {
+        accept.split(',').find_map(|media_type| {
+            match media_type.split(';').next().unwrap_or_default().trim() {
+                "application/json" => Some(Self::Json),
+                "application/vnd.apache.arrow.stream" => Some(Self::Arrow),
+                "text/csv" => Some(Self::Csv),
+                "text/tab-separated-values" => Some(Self::Tsv),
+                "text/markdown" => Some(Self::Markdown),
+                "text/plain" => Some(Self::Ascii),
+                _ => None,
+            }
+        })
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Arrow => "application/vnd.apache.arrow.stream",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Tsv => "text/tab-separated-values; charset=utf-8",
+            Self::Markdown => "text/markdown; charset=utf-8",
+            Self::Ascii => "text/plain; charset=utf-8",
+        }
+    }
+
+    /// Whether the last result of a text response was truncated, which its body cannot tell. JSON
+    /// bodies carry it in the result and Arrow streams in the schema metadata.
+    fn page_headers(&self, res: &SqlResponse) -> Vec<(&'static str, String)> {
+        let Some(last) = res.results.last() else {
+            return vec![];
+        };
+        if matches!(self, Self::Json | Self::Arrow) {
+            return vec![];
+        }
+        let mut headers = vec![("X-Truncated", last.truncated.to_string())];
+        if let Some(total_rows) = last.total_rows {
+            headers.push(("X-Total-Rows", total_rows.to_string()));
+        }
+        headers.push(("X-Offset", last.offset.to_string()));
+        headers.push(("X-Limit", last.limit.to_string()));
+        headers
+    }
+
+    /// Renders a response in any of the text based formats. Arrow streams are not built from a
+    /// `SqlResponse`, see `execute_statements_arrow`.
+    fn render(&self, mut res: SqlResponse) -> String {
+        if *self == Self::Json {
+            return json!(res).to_string();
+        }
+        let last = res.results.pop().unwrap_or_default();
+        match self {
+            Self::Csv => last.to_delimited(','),
+            Self::Tsv => last.to_delimited('\t'),
+            Self::Markdown => last.to_markdown(),
+            Self::Json | Self::Arrow | Self::Ascii => last.to_string(),
+        }
+    }
+}
+
+pub fn throw_error(
+    message: &str,
+    error: Option<Error>,
+    status_code: StatusCode,
+) -> Result<Response<Body>, Error> {
+    if let Some(error) = error {
+        eprintln!("error: {error}");
+    }
+
+    Ok(Response::builder()
+        .status(status_code)
+        .header("Content-Type", "application/json")
+        .body(json!({ "message": message }).to_string().into())?)
+}
+
+fn throw_sql_error(error: SqlError, status_code: StatusCode) -> Result<Response<Body>, Error> {
+    eprintln!("error: {error}");
+
+    Ok(Response::builder()
+        .status(status_code)
+        .header("Content-Type", "application/json")
+        .body(error.to_json().to_string().into())?)
+}
+
+/// Error raised while serving a [SqlRequest], pointing at the statement that caused it if any.
+#[derive(Debug)]
+struct SqlError {
+    /// Index of the failing statement.
+    statement: Option<usize>,
+    /// Position of the failing statement in `SqlRequest.sql`.
+    source: Option<SourceRange>,
+    error: DataFusionError,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    SqlParse,
+    Plan,
+    Schema,
+    Execution,
+    Resources,
+    Timeout,
+    Internal,
+    External,
+}
+
+impl SqlError {
+    fn in_statement(statement: usize, stmt: &SqlStatement) -> impl FnOnce(DataFusionError) -> Self {
+        let source = stmt.source;
+        move |error| Self {
+            statement: Some(statement),
+            source,
+            error,
+        }
+    }
+
+    /// Error that actually caused the failure, with the context, diagnostics and wrapping that
+    /// DataFusion adds on the way up stripped away.
+    fn root(&self) -> &DataFusionError {
+        let mut err = &self.error;
+        loop {
+            err = match err {
+                DataFusionError::Context(_, inner) | DataFusionError::Diagnostic(_, inner) => {
+                    inner.as_ref()
+                }
+                DataFusionError::Shared(inner) => inner.as_ref(),
+                DataFusionError::Collection(errs) if !errs.is_empty() => &errs[0],
+                DataFusionError::External(inner) => match inner.downcast_ref::<DataFusionError>() {
+                    Some(inner) => inner,
+                    None => return err,
+                },
+                _ => return err,
+            }
+        }
+    }
+
+    fn kind(&self) -> ErrorKind {
+        match self.root() {
+            DataFusionError::SQL(..) => ErrorKind::SqlParse,
+            DataFusionError::Plan(_)
+            | DataFusionError::NotImplemented(_)
+            | DataFusionError::Configuration(_) => ErrorKind::Plan,
+            DataFusionError::SchemaError(..) => ErrorKind::Schema,
+            DataFusionError::Execution(_) | DataFusionError::ArrowError(..) => ErrorKind::Execution,
+            DataFusionError::ResourcesExhausted(_) => ErrorKind::Resources,
+            DataFusionError::External(err) if err.is::<QueryTimeout>() => ErrorKind::Timeout,
+            DataFusionError::External(_)
+            | DataFusionError::IoError(_)
+            | DataFusionError::ObjectStore(_)
+            | DataFusionError::ParquetError(_) => ErrorKind::External,
+            _ => ErrorKind::Internal,
+        }
+    }
+
+    /// 4xx for mistakes in the submitted SQL, 5xx for failures of the engine or the environment
+    /// it runs on.
+    fn status_code(&self) -> StatusCode {
+        match self.root() {
+            DataFusionError::SQL(..) => StatusCode::BAD_REQUEST,
+            DataFusionError::Plan(_)
+            | DataFusionError::NotImplemented(_)
+            | DataFusionError::Configuration(_)
+            | DataFusionError::SchemaError(..)
+            | DataFusionError::Execution(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            DataFusionError::ArrowError(err, _) if is_invalid_data(err) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            DataFusionError::ResourcesExhausted(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            DataFusionError::External(err) if err.is::<QueryTimeout>() => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            DataFusionError::IoError(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Position of the offending SQL, relative to the `sql` script if the statement was split from
+    /// one, or to the failing statement's text otherwise. Only available when DataFusion attaches a
+    /// `Diagnostic` with a span to the error.
+    fn span(&self) -> Option<SourceRange> {
+        let span = self.error.diagnostic()?.span?;
+        let origin = self
+            .source
+            .map(|v| v.start)
+            .unwrap_or(SourcePosition::START);
+        Some(SourceRange {
+            start: origin.offset_by(span.start.into()),
+            end: origin.offset_by(span.end.into()),
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "message": self.error.to_string(),
+            "kind": self.kind(),
+            "statement": self.statement,
+            "span": self.span(),
+        })
+    }
+}
+
+/// Raised when a request runs for longer than its timeout.
+#[derive(Debug)]
+struct QueryTimeout(Duration);
+
+impl Display for QueryTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Query did not finish within the {:?} timeout", self.0)
+    }
+}
+
+impl std::error::Error for QueryTimeout {}
+
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    at: tokio::time::Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// Fails with [QueryTimeout] if `fut` is still running at the deadline. The future is dropped
+    /// then, and with it any record batch stream it was polling, which cancels the execution of
+    /// the physical plan. DataFusion operators yield cooperatively, so the deadline is honored even
+    /// in the middle of CPU heavy work.
+    async fn run<T>(
+        &self,
+        fut: impl Future<Output = datafusion::error::Result<T>>,
+    ) -> datafusion::error::Result<T> {
+        match tokio::time::timeout_at(self.at, fut).await {
+            Ok(result) => result,
+            Err(_) => Err(DataFusionError::External(Box::new(QueryTimeout(
+                self.timeout,
+            )))),
+        }
+    }
+}
+
+/// Arrow errors caused by the values a query operates on rather than by Arrow itself, like
+/// casting "foo" to an integer or dividing by zero.
+fn is_invalid_data(err: &ArrowError) -> bool {
+    matches!(
+        err,
+        ArrowError::CastError(_)
+            | ArrowError::ParseError(_)
+            | ArrowError::DivideByZero
+            | ArrowError::ArithmeticOverflow(_)
+            | ArrowError::InvalidArgumentError(_)
+    )
+}
+
+impl Display for SqlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.statement {
+            Some(statement) => write!(f, "statement {statement}: {}", self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl From<DataFusionError> for SqlError {
+    fn from(error: DataFusionError) -> Self {
+        Self {
+            statement: None,
+            source: None,
+            error,
+        }
+    }
+}
+
+impl From<SqlError> for DataFusionError {
+    fn from(error: SqlError) -> Self {
+        error.error
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PlanNode {
+    name: String,
+    /// The node as displayed in the indented plan.
+    description: String,
+    /// Expressions evaluated by the node. Only reported for logical plans, physical ones have them
+    /// in the description.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expressions: Option<Vec<String>>,
+    /// Output columns as (name, type) pairs.
+    schema: Vec<(String, String)>,
+    /// Output partitioning, only reported for physical plans.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partitioning: Option<String>,
+    /// For the nodes of a distributed plan that read the output of another stage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_stage: Option<StageRef>,
+    children: Vec<PlanNode>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StageRef {
+    stage: usize,
+    tasks: Vec<TaskAssignment>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TaskAssignment {
+    /// URL of the worker the task was assigned to, if it was assigned already.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worker: Option<String>,
+    /// Number of partitions the task produces.
+    partitions: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum DiagramFormat {
+    /// Graphviz DOT.
+    Dot,
+    Mermaid,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PlanDiagrams {
+    logical_plan: String,
+    physical_plan: String,
+    /// Only present for distributed plans.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stages: Option<String>,
+}
+
+/// A directed graph with labelled nodes, the common ground of the plan and stage diagrams.
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<String>,
+    /// (from, to, label) edges, from and to being indexes into `nodes`.
+    edges: Vec<(usize, usize, Option<String>)>,
+}
+
+impl Graph {
+    /// Edges go from the children to their parents, following the data.
+    fn from_plan_tree(root: &PlanNode) -> Self {
+        fn visit(node: &PlanNode, graph: &mut Graph) -> usize {
+            let id = graph.nodes.len();
+            graph.nodes.push(node.description.clone());
+            for child in &node.children {
+                let child_id = visit(child, graph);
+                graph.edges.push((child_id, id, None));
+            }
+            id
+        }
+        let mut graph = Self::default();
+        visit(root, &mut graph);
+        graph
+    }
+
+    /// Edges go from the stages producing data to the ones reading it, labelled with the
+    /// network boundary in between.
+    fn from_stages(stages: &[StageInfo]) -> Self {
+        let mut graph = Self::default();
+        for stage in stages {
+            graph.nodes.push(format!(
+                "Stage {}\n{} tasks x {} partitions",
+                stage.stage, stage.tasks, stage.partitions_per_task
+            ));
+        }
+        let index_of = |num: usize| stages.iter().position(|stage| stage.stage == num);
+        for (id, stage) in stages.iter().enumerate() {
+            for input in &stage.inputs {
+                if let Some(input_id) = index_of(input.stage) {
+                    graph
+                        .edges
+                        .push((input_id, id, Some(input.exchange.clone())));
+                }
+            }
+        }
+        graph
+    }
+
+    /// Labels may contain `\n` line breaks.
+    fn render(&self, format: DiagramFormat) -> String {
+        let mut out = String::new();
+        match format {
+            DiagramFormat::Dot => {
+                let escape = |label: &str| {
+                    label
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n")
+                };
+                out.push_str("digraph {\n  node [shape=box];\n");
+                for (id, label) in self.nodes.iter().enumerate() {
+                    out.push_str(&format!("  n{id} [label=\"{}\"];\n", escape(label)));
+                }
+                for (from, to, label) in &self.edges {
+                    match label {
+                        Some(label) => out.push_str(&format!(
+                            "  n{from} -> n{to} [label=\"{}\"];\n",
+                            escape(label)
+                        )),
+                        None => out.push_str(&format!("  n{from} -> n{to};\n")),
+                    }
+                }
+                out.push_str("}\n");
+            }
+            DiagramFormat::Mermaid => {
+                let escape = |label: &str| label.replace('"', "#quot;").replace('\n', "<br>");
+                out.push_str("flowchart BT\n");
+                for (id, label) in self.nodes.iter().enumerate() {
+                    out.push_str(&format!("  n{id}[\"{}\"]\n", escape(label)));
+                }
+                for (from, to, label) in &self.edges {
+                    match label {
+                        Some(label) => {
+                            out.push_str(&format!("  n{from} -->|{}| n{to}\n", escape(label)))
+                        }
+                        None => out.push_str(&format!("  n{from} --> n{to}\n")),
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StageInfo {
+    stage: usize,
+    tasks: usize,
+    /// Every task of the stage runs the whole fragment, producing this many partitions.
+    partitions_per_task: usize,
+    /// The stages this one reads from.
+    inputs: Vec<StageInput>,
+    /// The part of the plan run by the stage, with the network boundaries as leaves.
+    plan: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StageInput {
+    stage: usize,
+    /// The network boundary reading the stage, like `NetworkShuffleExec` or `NetworkCoalesceExec`.
+    exchange: String,
+    output_partitions: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Verification {
+    /// Whether both plans returned the same rows, ignoring their order.
+    matches: bool,
+    distributed_rows: usize,
+    single_node_rows: usize,
+    /// Rows returned by the distributed plan but not by the single node one, repeated as many
+    /// extra times as they were returned.
+    only_distributed: Vec<Vec<String>>,
+    /// Rows returned by the single node plan but not by the distributed one.
+    only_single_node: Vec<Vec<String>>,
+    distributed_plan: String,
+    single_node_plan: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OperatorMetrics {
+    /// Position of the operator in a depth-first walk of the plan, starting at 0 for the root.
+    /// Network boundaries are walked into the stage they read from.
+    id: usize,
+    parent: Option<usize>,
+    /// Stage the operator ran in, 0 being the head stage that runs on the coordinator.
+    stage: usize,
+    name: String,
+    /// Metric values summed across partitions, keyed by metric name. Times are in nanoseconds.
+    values: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct SqlResponse {
+    results: Vec<SqlResult>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct SqlResult {
+    columns: Vec<(String, String)>,
+    rows: Vec<Vec<String>>,
+    /// Same cells as `rows`, but as typed JSON values. Only present if `typed_values` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<Vec<Value>>>,
+    logical_plan: String,
+    physical_plan: String,
+    /// Physical plan rendered with execution metrics. Only present if `analyze` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    analyzed_plan: Option<String>,
+    /// Execution metrics of every operator in the physical plan. Only present if `analyze` was
+    /// requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics: Option<Vec<OperatorMetrics>>,
+    /// Whether the metrics only cover part of the execution, as it was cancelled once the
+    /// returned rows were gathered. Only present if `analyze` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_partial: Option<bool>,
+    /// Only present if `plan_trees` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logical_plan_tree: Option<PlanNode>,
+    /// Only present if `plan_trees` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    physical_plan_tree: Option<PlanNode>,
+    /// Only present if a `diagram` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diagrams: Option<PlanDiagrams>,
+    /// The stages of a distributed plan, sorted by stage number. The head stage, which runs on
+    /// the coordinator, is reported as stage 0. Absent for plans that are not distributed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stages: Option<Vec<StageInfo>>,
+    /// Only present on the last result if `verify` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verification: Option<Verification>,
+    /// Why the last statement was not verified although `verify` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verification_skipped: Option<String>,
+    /// Position of the statement in `SqlRequest.sql`, if it was split from it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<SourceRange>,
+    /// Number of rows inserted, updated or deleted, only set for DML and COPY statements.
+    affected_rows: Option<u64>,
+    /// Highest amount of memory reserved at once while running the statement on the coordinator.
+    /// Simulated workers have a pool of their own, shared by all requests, so their usage is not
+    /// included.
+    peak_memory_bytes: usize,
+    /// Number of spill files written by the operators of the statement, including the ones that
+    /// ran on workers.
+    spill_count: usize,
+    spilled_bytes: usize,
+    /// Whether the statement produced more rows than the ones returned.
+    truncated: bool,
+    /// Total number of rows produced by the statement. Unknown for truncated results whose row
+    /// count cannot be inferred from the plan statistics.
+    total_rows: Option<usize>,
+    /// Maximum number of rows that were going to be returned for this statement.
+    limit: usize,
+    /// Number of rows skipped before the first returned one.
+    offset: usize,
+    elapsed_ms: f64,
+}
+
+impl SqlResult {
+    fn to_table(&self) -> Table {
+        self.to_table_with(str::to_string)
+    }
+
+    /// Markdown table where cells cannot break the table layout, as GitHub renders `\|` as a
+    /// literal pipe and `<br>` as a line break inside cells.
+    fn to_markdown(&self) -> String {
+        self.to_table_with(|v| {
+            v.replace('|', "\\|")
+                .replace("\r\n", "<br>")
+                .replace('\n', "<br>")
+        })
+        .with(Style::markdown())
+        .to_string()
+    }
+
+    fn to_table_with(&self, cell: impl Fn(&str) -> String) -> Table {
+        let mut builder = Builder::new();
+        for (i, (name, typ)) in self.columns.iter().enumerate() {
+            let values = self.rows.iter().map(|v| cell(v.get(i).unwrap()));
+            builder.push_column(std::iter::once(cell(&format!("{name} [{typ}]"))).chain(values))
+        }
+        builder.build()
+    }
+
+    fn to_delimited(&self, delimiter: char) -> String {
+        let separator = delimiter.to_string();
+        let escape = |v: &str| {
+            if v.contains([delimiter, '"', '\n', '\r']) {
+                format!("\"{}\"", v.replace('"', "\"\""))
+            } else {
+                v.to_string()
+            }
+        };
+
+        let header = self.columns.iter().map(|(name, _)| escape(name));
+        let mut out = header.collect::<Vec<_>>().join(&separator);
+        out.push('\n');
+        for row in &self.rows {
+            out.push_str(
+                &row.iter()
+                    .map(|v| escape(v))
+                    .collect::<Vec<_>>()
+                    .join(&separator),
+            );
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl Display for SqlResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_table())
+    }
+}
+
+async fn execute_statements(req: SqlRequest, path: impl Display) -> Result<SqlResponse, SqlError> {
+    let deadline = req.deadline()?;
+    let memory_pool = Arc::new(PeakMemoryPool::new(req.memory_limit()?));
+    let ctx = deadline
+        .run(build_context(&req, path, memory_pool.clone()))
+        .await?;
+
+    let stmts = req.statements()?;
+    let mut results = Vec::with_capacity(stmts.len());
+    for (i, stmt) in stmts.iter().enumerate() {
+        let offset = if i == stmts.len() - 1 { req.offset } else { 0 };
+        let result = deadline
+            .run(execute_statement(
+                &ctx,
+                &stmt.sql,
+                &req,
+                offset,
+                req.verify && i == stmts.len() - 1,
+                &memory_pool,
+            ))
+            .await;
+        let mut result = result.map_err(SqlError::in_statement(i, stmt))?;
+        result.source = stmt.source;
+        results.push(result);
+    }
+
+    Ok(SqlResponse { results })
+}
+
+/// Runs all the statements, and encodes the output of the last one as an Arrow IPC stream,
+/// skipping any string formatting.
+async fn execute_statements_arrow(
+    req: SqlRequest,
+    path: impl Display,
+) -> Result<Vec<u8>, SqlError> {
+    let deadline = req.deadline()?;
+    let memory_pool = Arc::new(PeakMemoryPool::new(req.memory_limit()?));
+    let ctx = deadline.run(build_context(&req, path, memory_pool)).await?;
+
+    let stmts = req.statements()?;
+    let Some((last, stmts)) = stmts.split_last() else {
+        return Ok(write_arrow_stream(&Schema::empty(), &[]).map_err(DataFusionError::from)?);
+    };
+    // Earlier statements are paged like the last one, so they stop as soon as their output is
+    // past the limit, even though only the last one is returned.
+    let limit = req.row_limit();
+    for (i, stmt) in stmts.iter().enumerate() {
+        deadline
+            .run(execute_statement_page(&ctx, &stmt.sql, 0, limit))
+            .await
+            .map_err(SqlError::in_statement(i, stmt))?;
+    }
+
+    let page = deadline
+        .run(execute_statement_page(&ctx, &last.sql, req.offset, limit))
+        .await
+        .map_err(SqlError::in_statement(stmts.len(), last))?;
+
+    let mut metadata = page.schema.metadata().clone();
+    metadata.insert("truncated".to_string(), page.truncated.to_string());
+    if let Some(total_rows) = page.total_rows {
+        metadata.insert("total_rows".to_string(), total_rows.to_string());
+    }
+    metadata.insert("offset".to_string(), req.offset.to_string());
+    metadata.insert("limit".to_string(), limit.to_string());
+    let schema = Arc::new(page.schema.as_ref().clone().with_metadata(metadata));
+    let record_batches = page
+        .record_batches
+        .into_iter()
+        .map(|batch| batch.with_schema(schema.clone()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(DataFusionError::from)?;
+    Ok(write_arrow_stream(&schema, &record_batches).map_err(DataFusionError::from)?)
+}
+
+/// A page of the output of a statement, see [collect_page].
+struct StatementPage {
+    schema: SchemaRef,
+    record_batches: Vec<RecordBatch>,
+    truncated: bool,
+    total_rows: Option<usize>,
+}
+
+/// Runs `stmt` and gathers a page of its output.
+async fn execute_statement_page(
+    ctx: &SessionContext,
+    stmt: &str,
+    offset: usize,
+    limit: usize,
+) -> datafusion::error::Result<StatementPage> {
+    let df = ctx.sql(stmt).await?;
+    // The affected rows count is the only row DML statements produce, it must never be skipped.
+    let offset = if is_dml(df.logical_plan()) { 0 } else { offset };
+    let physical_plan = df.create_physical_plan().await?;
+    let stream = execute_stream(physical_plan.clone(), ctx.task_ctx())?;
+    let (record_batches, skipped, truncated) = collect_page(stream, offset, limit).await?;
+    let total_rows = if truncated {
+        exact_num_rows(&physical_plan)
+    } else {
+        Some(skipped + record_batches.iter().map(|b| b.num_rows()).sum::<usize>())
+    };
+    Ok(StatementPage {
+        schema: physical_plan.schema(),
+        record_batches,
+        truncated,
+        total_rows,
+    })
+}
+
+fn write_arrow_stream(
+    schema: &Schema,
+    record_batches: &[RecordBatch],
+) -> Result<Vec<u8>, ArrowError> {
+    let mut writer = StreamWriter::try_new(vec![], schema)?;
+    for batch in record_batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    writer.into_inner()
+}
+
+/// A single statement to run, along with its position in `SqlRequest.sql` if split from it.
+struct SqlStatement {
+    sql: String,
+    source: Option<SourceRange>,
+}
+
+/// Line and column in some SQL text, both starting at 1, as reported by sqlparser.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct SourcePosition {
+    line: u64,
+    column: u64,
+}
+
+/// Range of SQL text. `end` points right past the last character.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct SourceRange {
+    start: SourcePosition,
+    end: SourcePosition,
+}
+
+impl SourcePosition {
+    const START: Self = Self { line: 1, column: 1 };
+
+    fn at_byte_offset(text: &str, offset: usize) -> Self {
+        let before = &text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            line: before.matches('\n').count() as u64 + 1,
+            column: before[line_start..].chars().count() as u64 + 1,
+        }
+    }
+
+    fn byte_offset(&self, text: &str) -> usize {
+        let line_start: usize = text
+            .split_inclusive('\n')
+            .take(self.line.saturating_sub(1) as usize)
+            .map(str::len)
+            .sum();
+        let column: usize = text[line_start..]
+            .chars()
+            .take(self.column.saturating_sub(1) as usize)
+            .map(char::len_utf8)
+            .sum();
+        line_start + column
+    }
+
+    /// Translates `position`, relative to a text that starts at `self`, to the enclosing text.
+    fn offset_by(&self, position: SourcePosition) -> SourcePosition {
+        if position.line == 1 {
+            SourcePosition {
+                line: self.line,
+                column: self.column + position.column - 1,
+            }
+        } else {
+            SourcePosition {
+                line: self.line + position.line - 1,
+                column: position.column,
+            }
+        }
+    }
+}
+
+impl From<Location> for SourcePosition {
+    fn from(location: Location) -> Self {
+        Self {
+            line: location.line,
+            column: location.column,
+        }
+    }
+}
+
+/// Diagnostic spans use DataFusion's own copy of sqlparser's [Location].
+impl From<datafusion::common::Location> for SourcePosition {
+    fn from(location: datafusion::common::Location) -> Self {
+        Self {
+            line: location.line,
+            column: location.column,
+        }
+    }
+}
+
+/// Splits a SQL script with DataFusion's parser, so semicolons inside string literals, comments
+/// or function bodies are not taken as statement boundaries. Each statement's text is sliced from
+/// the script as written.
+fn split_statements(sql: &str) -> Result<Vec<SqlStatement>, SqlError> {
+    let dialect = GenericDialect {};
+    let mut parser = DFParserBuilder::new(sql).with_dialect(&dialect).build()?;
+
+    let mut stmts = vec![];
+    loop {
+        while parser.parser.consume_token(&Token::SemiColon) {}
+        let first = parser.parser.peek_token();
+        if first == Token::EOF {
+            break;
+        }
+
+        let statement = stmts.len();
+        let in_statement = |error| SqlError {
+            statement: Some(statement),
+            source: None,
+            error,
+        };
+        parser.parse_statement().map_err(in_statement)?;
+        let next = parser.parser.peek_token();
+        let start = SourcePosition::from(first.span.start).byte_offset(sql);
+        let end = match next.token {
+            Token::EOF => sql.len(),
+            _ => SourcePosition::from(next.span.start).byte_offset(sql),
+        };
+        let text = sql[start..end].trim_end();
+        if next != Token::EOF {
+            parser
+                .parser
+                .expect_token(&Token::SemiColon)
+                .map_err(|err| in_statement(err.into()))?;
+        }
+
+        stmts.push(SqlStatement {
+            sql: text.to_string(),
+            source: Some(SourceRange {
+                start: SourcePosition::at_byte_offset(sql, start),
+                end: SourcePosition::at_byte_offset(sql, start + text.len()),
+            }),
+        });
+    }
+    Ok(stmts)
+}
+
+async fn build_context(
+    req: &SqlRequest,
+    path: impl Display,
+    memory_pool: Arc<PeakMemoryPool>,
+) -> datafusion::error::Result<SessionContext> {
+    let cfg = SessionConfig::new()
+        .with_information_schema(true)
+        .set_bool("datafusion.sql_parser.collect_spans", true);
+
+    let limit = req.row_limit();
+    if !(1..=MAX_ROW_LIMIT).contains(&limit) {
+        return Err(DataFusionError::Configuration(format!(
+            "limit must be between 1 and {MAX_ROW_LIMIT}, got {limit}"
+        )));
+    }
+    let workers = req.workers.unwrap_or(DEFAULT_WORKERS);
+    if !(1..=MAX_WORKERS).contains(&workers) {
+        return Err(DataFusionError::Configuration(format!(
+            "workers must be between 1 and {MAX_WORKERS}, got {workers}"
+        )));
+    }
+    let network = req.network()?;
+    if let Some(fault) = req.faults.iter().find(|fault| fault.worker >= workers) {
+        return Err(DataFusionError::Configuration(format!(
+            "Cannot inject a fault in worker {}, there are only {workers} workers",
+            fault.worker
+        )));
+    }
+    for (i, fault) in req.faults.iter().enumerate() {
+        if req.faults[..i].iter().any(|f| f.worker == fault.worker) {
+            return Err(DataFusionError::Configuration(format!(
+                "Cannot inject more than one fault in worker {}",
+                fault.worker
+            )));
+        }
+    }
+    for (i, straggler) in req.stragglers.iter().enumerate() {
+        if straggler.worker >= workers {
+            return Err(DataFusionError::Configuration(format!(
+                "Cannot slow down worker {}, there are only {workers} workers",
+                straggler.worker
+            )));
+        }
+        if req.stragglers[..i]
+            .iter()
+            .any(|s| s.worker == straggler.worker)
+        {
+            return Err(DataFusionError::Configuration(format!(
+                "Cannot slow down worker {} more than once",
+                straggler.worker
+            )));
+        }
+        if !(1.0..=MAX_SLOWDOWN).contains(&straggler.slowdown) {
+            return Err(DataFusionError::Configuration(format!(
+                "slowdown must be between 1 and {MAX_SLOWDOWN}, got {}",
+                straggler.slowdown
+            )));
+        }
+    }
+
+    let runtime_env = RuntimeEnvBuilder::new()
+        .with_memory_pool(memory_pool)
+        .with_disk_manager_builder(DiskManagerBuilder::default())
+        .build_arc()?;
+
+    let mut builder = SessionStateBuilder::new()
+        .with_default_features()
+        .with_config(cfg)
+        .with_runtime_env(runtime_env);
+    match REMOTE_CHANNEL_RESOLVER.as_ref() {
+        Ok(Some(resolver)) => {
+            if req.workers.is_some()
+                || network != NetworkConditions::default()
+                || !req.faults.is_empty()
+                || !req.stragglers.is_empty()
+            {
+                return Err(DataFusionError::Configuration(
+                    "workers, network conditions, faults and stragglers can only be set on \
+                     simulated workers"
+                        .to_string(),
+                ));
+            }
+            builder = builder.with_distributed_channel_resolver(resolver.clone());
+        }
+        Ok(None) => {
+            builder = builder.with_distributed_channel_resolver(
+                CHANNEL_RESOLVER
+                    .with_workers(workers)
+                    .with_network(network)
+                    .with_faults(req.faults.clone())
+                    .with_stragglers(req.stragglers.clone()),
+            );
+        }
+        // A misconfigured deployment rather than a mistake in the request.
+        Err(err) => return Err(DataFusionError::External(err.clone().into())),
+    }
+    if req.is_distributed() {
+        builder = builder.with_physical_optimizer_rule(Arc::new(DistributedPhysicalOptimizerRule))
+    }
+    let mut state = builder.build();
+    let options = state.config_mut().options_mut();
+    if let Some(files_per_task) = req.files_per_task {
+        options.set("distributed.files_per_task", &files_per_task.to_string())?;
+    }
+    if let Some(factor) = req.cardinality_task_count_factor {
+        options.set(
+            "distributed.cardinality_task_count_factor",
+            &factor.to_string(),
+        )?;
+    }
+    let ctx = SessionContext::new_with_state(state);
+    load_parquet_files(path.to_string(), &ctx).await?;
+    Ok(ctx)
+}
+
+async fn execute_statement(
+    ctx: &SessionContext,
+    stmt: &str,
+    req: &SqlRequest,
+    offset: usize,
+    verify: bool,
+    memory_pool: &PeakMemoryPool,
+) -> datafusion::error::Result<SqlResult> {
+    let start = Instant::now();
+    memory_pool.reset_peak();
+    let options = FormatOptions::default().with_display_error(true);
+
+    let df = ctx.sql(stmt).await?;
+    let logical_plan_str = df.logical_plan().display_indent().to_string();
+    let logical_plan_tree = req.plan_trees.then(|| logical_plan_tree(df.logical_plan()));
+    let logical_plan_diagram = req
+        .diagram
+        .map(|format| Graph::from_plan_tree(&logical_plan_tree(df.logical_plan())).render(format));
+    let is_dml = is_dml(df.logical_plan());
+    let mut verification_skipped = None;
+    let verify_plan = match verify.then(|| unverifiable_reason(df.logical_plan())) {
+        Some(Some(reason)) => {
+            verification_skipped = Some(reason);
+            None
+        }
+        Some(None) => Some(df.logical_plan().clone()),
+        None => None,
+    };
+
+    let physical_plan = df.create_physical_plan().await?;
+
+    // The affected rows count is the only row DML statements produce, it must never be skipped.
+    let offset = if is_dml { 0 } else { offset };
+    let mut stream = execute_stream(physical_plan.clone(), ctx.task_ctx())?;
+    // Verifying needs every row, so they are pulled upfront and replayed for the page.
+    let mut verify_batches = None;
+    if verify_plan.is_some() {
+        let (batches, complete) = collect_at_most(&mut stream, MAX_VERIFY_ROWS).await?;
+        let replay = futures::stream::iter(batches.clone().into_iter().map(Ok)).chain(stream);
+        stream = Box::pin(RecordBatchStreamAdapter::new(
+            physical_plan.schema(),
+            replay,
+        ));
+        verify_batches = complete.then_some(batches);
+    }
+    let limit = req.row_limit();
+    let (record_batches, skipped, truncated) = collect_page(stream, offset, limit).await?;
+    // The plan as it ran, with its tasks assigned to workers.
+    let metrics_plan = with_worker_metrics(&physical_plan);
+    let (spill_count, spilled_bytes) = spill_metrics(&metrics_plan);
+
+    let affected_rows = if is_dml {
+        Some(count_affected_rows(&record_batches))
+    } else {
+        None
+    };
+
+    // Taken from the plan rather than from the batches, as a page past the end has none.
+    let columns = schema_columns(&physical_plan.schema().fields);
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut values: Option<Vec<Vec<Value>>> = req.typed_values.then(Vec::new);
+    for record_batch in record_batches {
+        rows.extend(format_rows(&record_batch, &options)?);
+
+        if let Some(values) = &mut values {
+            values.extend(batch_to_json_values(&record_batch)?);
+        }
+    }
+    // Taken before verifying, so they only account for the statement itself.
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let peak_memory_bytes = memory_pool.peak();
+
+    let mut verification = None;
+    if let Some(logical_plan) = &verify_plan {
+        let compared = match &verify_batches {
+            Some(batches) => {
+                let state = ctx.state();
+                let distributed = req.is_distributed();
+                verify_statement(
+                    &state,
+                    logical_plan,
+                    &physical_plan,
+                    batches,
+                    distributed,
+                    limit,
+                )
+                .await?
+            }
+            None => Err(too_many_rows_to_verify()),
+        };
+        match compared {
+            Ok(compared) => verification = Some(compared),
+            Err(reason) => verification_skipped = Some(reason),
+        }
+    }
+
+    let stages = distributed_stages(&physical_plan);
+    let total_rows = if truncated {
+        exact_num_rows(&physical_plan)
+    } else {
+        Some(skipped + rows.len())
+    };
+
+    Ok(SqlResult {
+        columns,
+        rows,
+        values,
+        logical_plan: logical_plan_str,
+        physical_plan: display_physical_plan(&physical_plan, false),
+        analyzed_plan: req
+            .analyze
+            .then(|| display_physical_plan(&metrics_plan, true)),
+        metrics: req.analyze.then(|| operator_metrics(&metrics_plan)),
+        metrics_partial: req.analyze.then_some(truncated),
+        logical_plan_tree,
+        physical_plan_tree: req.plan_trees.then(|| physical_plan_tree(&metrics_plan)),
+        diagrams: req.diagram.map(|format| PlanDiagrams {
+            logical_plan: logical_plan_diagram.unwrap_or_default(),
+            physical_plan: Graph::from_plan_tree(&physical_plan_tree(&metrics_plan)).render(format),
+            stages: stages
+                .as_deref()
+                .map(|stages| Graph::from_stages(stages).render(format)),
+        }),
+        stages,
+        verification,
+        verification_skipped,
+        affected_rows,
+        peak_memory_bytes,
+        spill_count,
+        spilled_bytes,
+        truncated,
+        total_rows,
+        limit,
+        offset,
+        elapsed_ms,
+    })
+}
+
+/// Whether `plan` inserts, updates or deletes rows, returning only how many it affected.
+fn is_dml(plan: &LogicalPlan) -> bool {
+    matches!(plan, LogicalPlan::Dml(_) | LogicalPlan::Copy(_))
+}
+
+/// Pulls batches from `stream`, skipping the first `offset` rows and gathering up to `limit` rows
+/// after them. Skipped rows are sliced away without ever being formatted, and the stream is dropped
+/// as soon as it yields a row past the page, which cancels the rest of the execution. Returns the
+/// gathered batches, the number of skipped rows and whether any row was left out after the page.
+async fn collect_page(
+    mut stream: SendableRecordBatchStream,
+    offset: usize,
+    limit: usize,
+) -> datafusion::error::Result<(Vec<RecordBatch>, usize, bool)> {
+    let mut record_batches = vec![];
+    let mut skipped = 0;
+    let mut remaining = limit;
+    while let Some(mut batch) = stream.try_next().await? {
+        if skipped < offset {
+            let skip = (offset - skipped).min(batch.num_rows());
+            skipped += skip;
+            batch = batch.slice(skip, batch.num_rows() - skip);
+        }
+        if batch.num_rows() > remaining {
+            if remaining > 0 {
+                record_batches.push(batch.slice(0, remaining));
+            }
+            return Ok((record_batches, skipped, true));
+        }
+        remaining -= batch.num_rows();
+        if batch.num_rows() > 0 {
+            record_batches.push(batch);
+        }
+    }
+    Ok((record_batches, skipped, false))
+}
+
+/// Returns a copy of an executed distributed plan as it ran, with its tasks assigned to workers
+/// and the operators of the stages that ran on them carrying the metrics they sent back. Plans
+/// that are not distributed are returned as they are.
+fn with_worker_metrics(physical_plan: &Arc<dyn ExecutionPlan>) -> Arc<dyn ExecutionPlan> {
+    if distributed_stages(physical_plan).is_none() {
+        return physical_plan.clone();
+    }
+    rewrite_distributed_plan_with_metrics(
+        physical_plan.clone(),
+        DistributedMetricsFormat::Aggregated,
+    )
+    .unwrap_or_else(|_| physical_plan.clone())
+}
+
+/// The stage a network boundary reads from, `None` for any other plan node.
+fn input_stage(plan: &Arc<dyn ExecutionPlan>) -> Option<&Stage> {
+    plan.as_network_boundary()
+        .and_then(|boundary| boundary.input_stage())
+}
+
+/// Children of a plan node, with network boundaries leading to the plan of the stage they read.
+fn plan_inputs(plan: &Arc<dyn ExecutionPlan>) -> Vec<&Arc<dyn ExecutionPlan>> {
+    match input_stage(plan) {
+        Some(stage) => stage.plan.decoded().into_iter().collect(),
+        None => plan.children(),
+    }
+}
+
+/// Adds up the spill metrics of all the operators in an executed plan, across all its stages.
+fn spill_metrics(physical_plan: &Arc<dyn ExecutionPlan>) -> (usize, usize) {
+    let (mut spill_count, mut spilled_bytes) = physical_plan
+        .metrics()
+        .map(|m| (m.spill_count().unwrap_or(0), m.spilled_bytes().unwrap_or(0)))
+        .unwrap_or_default();
+    for child in plan_inputs(physical_plan) {
+        let (count, bytes) = spill_metrics(child);
+        spill_count += count;
+        spilled_bytes += bytes;
+    }
+    (spill_count, spilled_bytes)
+}
+
+fn schema_columns(fields: &Fields) -> Vec<(String, String)> {
+    fields
+        .iter()
+        .map(|e| (e.name().to_string(), e.data_type().to_string()))
+        .collect()
+}
+
+fn logical_plan_tree(plan: &LogicalPlan) -> PlanNode {
+    let description = plan.display().to_string();
+    PlanNode {
+        name: description
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        description,
+        expressions: Some(plan.expressions().iter().map(|e| e.to_string()).collect()),
+        schema: schema_columns(plan.schema().fields()),
+        partitioning: None,
+        input_stage: None,
+        children: plan.inputs().into_iter().map(logical_plan_tree).collect(),
+    }
+}
+
+/// Network boundaries of distributed plans are walked into the plan of the stage they read from,
+/// so the tree spans all the stages.
+fn physical_plan_tree(plan: &Arc<dyn ExecutionPlan>) -> PlanNode {
+    PlanNode {
+        name: plan.name().to_string(),
+        description: strip_current_dir(
+            displayable(plan.as_ref()).one_line().to_string().trim_end(),
+        ),
+        expressions: None,
+        schema: schema_columns(&plan.schema().fields),
+        partitioning: Some(plan.properties().output_partitioning().to_string()),
+        input_stage: input_stage(plan).map(stage_ref),
+        children: plan_inputs(plan)
+            .into_iter()
+            .map(physical_plan_tree)
+            .collect(),
+    }
+}
+
+/// Every task of a stage runs the whole stage plan, so they all produce as many partitions.
+fn stage_ref(stage: &Stage) -> StageRef {
+    let partitions = stage.plan.decoded().map_or(0, |plan| {
+        plan.properties().output_partitioning().partition_count()
+    });
+    StageRef {
+        stage: stage.num,
+        tasks: stage
+            .tasks
+            .iter()
+            .map(|task| TaskAssignment {
+                // Simulated workers carry their network conditions and faults in the query.
+                worker: task.url.as_ref().map(|url| {
+                    let mut url = url.clone();
+                    url.set_query(None);
+                    url.to_string()
+                }),
+                partitions,
+            })
+            .collect(),
+    }
+}
+
+fn distributed_stages(plan: &Arc<dyn ExecutionPlan>) -> Option<Vec<StageInfo>> {
+    fn visit(num: usize, tasks: usize, plan: &Arc<dyn ExecutionPlan>, out: &mut Vec<StageInfo>) {
+        let mut stage = StageInfo {
+            stage: num,
+            tasks,
+            partitions_per_task: plan.properties().output_partitioning().partition_count(),
+            inputs: vec![],
+            plan: String::new(),
+        };
+        let mut input_stages = vec![];
+        fragment(plan, 0, &mut stage, &mut input_stages);
+        out.push(stage);
+        for input in input_stages {
+            if let Ok(input_plan) = input.plan.decoded() {
+                visit(input.num, input.tasks.len(), input_plan, out);
+            }
+        }
+    }
+
+    fn fragment<'a>(
+        plan: &'a Arc<dyn ExecutionPlan>,
+        indent: usize,
+        stage: &mut StageInfo,
+        input_stages: &mut Vec<&'a Stage>,
+    ) {
+        let line = displayable(plan.as_ref()).one_line().to_string();
+        stage.plan.push_str(&"  ".repeat(indent));
+        stage.plan.push_str(&strip_current_dir(line.trim_end()));
+        stage.plan.push('\n');
+        if let Some(input) = input_stage(plan) {
+            stage.inputs.push(StageInput {
+                stage: input.num,
+                exchange: plan.name().to_string(),
+                output_partitions: plan.properties().output_partitioning().partition_count(),
+            });
+            input_stages.push(input);
+            return;
+        }
+        for child in plan.children() {
+            fragment(child, indent + 1, stage, input_stages);
+        }
+    }
+
+    let mut stages = vec![];
+    visit(0, 1, plan, &mut stages);
+    if stages.len() == 1 {
+        return None;
+    }
+    stages.sort_by_key(|stage| stage.stage);
+    Some(stages)
+}
+
+/// Flattens the metrics of an executed plan into one entry per operator, across all its stages.
+/// Expects the plan returned by [with_worker_metrics] for distributed plans.
+fn operator_metrics(physical_plan: &Arc<dyn ExecutionPlan>) -> Vec<OperatorMetrics> {
+    fn visit(
+        plan: &Arc<dyn ExecutionPlan>,
+        parent: Option<usize>,
+        stage: usize,
+        out: &mut Vec<OperatorMetrics>,
+    ) {
+        let id = out.len();
+        let values = plan
+            .metrics()
+            .map(|metrics| {
+                metrics
+                    .aggregate_by_name()
+                    .iter()
+                    .map(|m| (m.value().name().to_string(), m.value().as_usize()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        out.push(OperatorMetrics {
+            id,
+            parent,
+            stage,
+            name: plan.name().to_string(),
+            values,
+        });
+        let child_stage = input_stage(plan).map_or(stage, |input| input.num);
+        for child in plan_inputs(plan) {
+            visit(child, Some(id), child_stage, out);
+        }
+    }
+
+    let mut out = vec![];
+    visit(physical_plan, None, 0, &mut out);
+    out
+}
+
+/// Number of rows the plan produces, if known upfront without executing it.
+fn exact_num_rows(physical_plan: &Arc<dyn ExecutionPlan>) -> Option<usize> {
+    match physical_plan.partition_statistics(None).ok()?.num_rows {
+        Precision::Exact(num_rows) => Some(num_rows),
+        _ => None,
+    }
+}
+
+fn format_rows(
+    batch: &RecordBatch,
+    options: &FormatOptions,
+) -> Result<Vec<Vec<String>>, ArrowError> {
+    let per_column_formatters = batch
+        .columns()
+        .iter()
+        .map(|c| ArrayFormatter::try_new(c.as_ref(), options))
+        .collect::<Result<Vec<_>, ArrowError>>()?;
+
+    let mut rows = Vec::with_capacity(batch.num_rows());
+    for i in 0..batch.num_rows() {
+        let mut row: Vec<String> = vec![];
+        for formatter in &per_column_formatters {
+            row.push(formatter.value(i).to_string());
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Why `plan` cannot be verified, if it cannot. Statements other than queries already ran and
+/// would run again, and EXPLAIN returns the plan of each side rather than comparable rows.
+fn unverifiable_reason(plan: &LogicalPlan) -> Option<String> {
+    match plan {
+        LogicalPlan::Dml(_)
+        | LogicalPlan::Copy(_)
+        | LogicalPlan::Ddl(_)
+        | LogicalPlan::Statement(_) => {
+            Some("Only queries are verified, as verifying runs the statement again".to_string())
+        }
+        LogicalPlan::Explain(_) | LogicalPlan::Analyze(_) => {
+            Some("EXPLAIN is not verified, as each side returns its own plan".to_string())
+        }
+        _ => None,
+    }
+}
+
+fn too_many_rows_to_verify() -> String {
+    format!("Only results of up to {MAX_VERIFY_ROWS} rows are verified, add a LIMIT to the query")
+}
+
+/// Pulls batches from `stream` until it ends or returns more than `max_rows` rows. Returns the
+/// pulled batches and whether the stream ended.
+async fn collect_at_most(
+    stream: &mut SendableRecordBatchStream,
+    max_rows: usize,
+) -> datafusion::error::Result<(Vec<RecordBatch>, bool)> {
+    let mut batches = vec![];
+    let mut rows = 0;
+    while let Some(batch) = stream.try_next().await? {
+        rows += batch.num_rows();
+        batches.push(batch);
+        if rows > max_rows {
+            return Ok((batches, false));
+        }
+    }
+    Ok((batches, true))
+}
+
+/// Runs `logical_plan` once more with the distributed planner toggled, and compares the
+/// formatted rows it returns with the ones of the main run regardless of their order. Up to
+/// `limit` differing rows are reported on each side. If the second run returns too many rows to
+/// compare, the reason why nothing was compared is returned instead.
+async fn verify_statement(
+    state: &SessionState,
+    logical_plan: &LogicalPlan,
+    main_plan: &Arc<dyn ExecutionPlan>,
+    main_batches: &[RecordBatch],
+    distributed: bool,
+    limit: usize,
+) -> datafusion::error::Result<Result<Verification, String>> {
+    let distributed_rule = DistributedPhysicalOptimizerRule;
+    let mut rules = state
+        .physical_optimizers()
+        .iter()
+        .filter(|rule| rule.name() != distributed_rule.name())
+        .cloned()
+        .collect::<Vec<_>>();
+    if !distributed {
+        rules.push(Arc::new(distributed_rule));
+    }
+    let state = SessionStateBuilder::new_from_existing(state.clone())
+        .with_physical_optimizer_rules(rules)
+        .build();
+    let other_plan = state.create_physical_plan(logical_plan).await?;
+    let mut stream = execute_stream(other_plan.clone(), state.task_ctx())?;
+    let (other_batches, complete) = collect_at_most(&mut stream, MAX_VERIFY_ROWS).await?;
+    if !complete {
+        return Ok(Err(too_many_rows_to_verify()));
+    }
+
+    let options = FormatOptions::default().with_display_error(true);
+    let format_all = |batches: &[RecordBatch]| {
+        let mut rows = vec![];
+        for batch in batches {
+            rows.extend(format_rows(batch, &options)?);
+        }
+        Ok::<_, ArrowError>(rows)
+    };
+    let main = (
+        display_physical_plan(main_plan, false),
+        format_all(main_batches)?,
+    );
+    let other = (
+        display_physical_plan(&other_plan, false),
+        format_all(&other_batches)?,
+    );
+    let ((distributed_plan, distributed_rows), (single_node_plan, single_node_rows)) =
+        if distributed {
+            (main, other)
+        } else {
+            (other, main)
+        };
+
+    // Positive counts are rows the distributed plan returned more times, negative ones rows the
+    // single node plan returned more times.
+    let mut counts = BTreeMap::<&Vec<String>, isize>::new();
+    for row in &distributed_rows {
+        *counts.entry(row).or_default() += 1;
+    }
+    for row in &single_node_rows {
+        *counts.entry(row).or_default() -= 1;
+    }
+    let mut only_distributed = vec![];
+    let mut only_single_node = vec![];
+    for (row, count) in counts {
+        let side = if count > 0 {
+            &mut only_distributed
+        } else {
+            &mut only_single_node
+        };
+        for _ in 0..count.unsigned_abs() {
+            side.push(row.clone());
+        }
+    }
+
+    Ok(Ok(Verification {
+        matches: only_distributed.is_empty() && only_single_node.is_empty(),
+        distributed_rows: distributed_rows.len(),
+        single_node_rows: single_node_rows.len(),
+        only_distributed: only_distributed.into_iter().take(limit).collect(),
+        only_single_node: only_single_node.into_iter().take(limit).collect(),
+        distributed_plan,
+        single_node_plan,
+    }))
+}
+
+/// Encodes every cell of `batch` with Arrow's JSON writer, so numbers, booleans, nulls, structs
+/// and lists keep their type. Columns are encoded one by one, as rows are returned positionally
+/// and column names are not guaranteed to be unique.
+fn batch_to_json_values(batch: &RecordBatch) -> Result<Vec<Vec<Value>>, ArrowError> {
+    let mut rows = vec![Vec::with_capacity(batch.num_columns()); batch.num_rows()];
+    for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+        let schema = Schema::new(vec![field.as_ref().clone().with_name("v")]);
+        let column_batch = RecordBatch::try_new(Arc::new(schema), vec![column.clone()])?;
+
+        let mut writer = WriterBuilder::new()
+            .with_explicit_nulls(true)
+            .build::<_, JsonArray>(vec![]);
+        writer.write(&column_batch)?;
+        writer.finish()?;
+
+        let cells: Vec<Map<String, Value>> = serde_json::from_slice(&writer.into_inner())
+            .map_err(|err| ArrowError::JsonError(err.to_string()))?;
+        for (row, mut cell) in rows.iter_mut().zip(cells) {
+            row.push(cell.remove("v").unwrap_or(Value::Null));
+        }
+    }
+    Ok(rows)
+}
+
+/// DML and COPY statements report the number of affected rows as a single UInt64 "count" column.
+fn count_affected_rows(record_batches: &[RecordBatch]) -> u64 {
+    record_batches
+        .iter()
+        .filter(|batch| batch.num_columns() > 0)
+        .filter_map(|batch| batch.column(0).as_primitive_opt::<UInt64Type>())
+        .flat_map(|array| array.iter().flatten())
+        .sum()
+}
+
+fn display_physical_plan(physical_plan: &Arc<dyn ExecutionPlan>, show_metrics: bool) -> String {
+    strip_current_dir(&display_plan_ascii(physical_plan.as_ref(), show_metrics))
+}
+
+/// Hides where the fiddle is deployed from the file paths that plans display.
+fn strip_current_dir(text: &str) -> String {
+    match current_dir() {
+        Ok(curr_dir) => text.replace(curr_dir.display().to_string().trim_start_matches("/"), ""),
+        Err(_) => text.to_string(),
+    }
+}
+
+async fn load_parquet_files(base: String, ctx: &SessionContext) -> Result<(), DataFusionError> {
+    let mut futures = vec![];
+    for entry in fs::read_dir(&base)? {
+        let entry_path = entry?.path();
+        let file_name = entry_path.file_name().unwrap().display().to_string();
+        let file_path = format!("{base}/{file_name}");
+
+        let fut = ctx.register_parquet(file_name, file_path, ParquetReadOptions::default());
+        futures.push(fut);
+    }
+
+    for result in futures::future::join_all(futures).await {
+        result?
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        execute_statements, execute_statements_arrow, DiagramFormat, ErrorKind, Fault,
+        InMemoryChannelResolver, OutputFormat, PlanNode, SourcePosition, SourceRange, SqlError,
+        SqlRequest, SqlResult, StageRef, Straggler, WorkerFault, CHANNEL_RESOLVER,
+        DEFAULT_ROW_LIMIT, MAX_ROW_LIMIT, MAX_VERIFY_ROWS, MAX_WORKERS,
+    };
+    use datafusion::arrow::datatypes::DataType;
+    use datafusion::arrow::ipc::reader::StreamReader;
+    use datafusion_distributed::ChannelResolver;
+    use serde_json::json;
+    use std::io::Cursor;
+    use vercel_runtime::StatusCode;
+
+    #[tokio::test]
+    async fn test_create_table() -> datafusion::error::Result<()> {
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec![
+                    "CREATE TABLE book (str text)".to_string(),
+                    "INSERT INTO book (str) VALUES ('foo')".to_string(),
+                    "SELECT * FROM book".to_string(),
+                ],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+
+        insta::assert_snapshot!(result, @r"
+        +----------------+
+        | str [Utf8View] |
+        +----------------+
+        | foo            |
+        +----------------+
+        ");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_result_per_statement() -> datafusion::error::Result<()> {
+        let results = execute_statements(
+            SqlRequest {
+                stmts: vec![
+                    "CREATE TABLE book (str text)".to_string(),
+                    "INSERT INTO book (str) VALUES ('foo'), ('bar')".to_string(),
+                    "SELECT * FROM book ORDER BY str".to_string(),
+                ],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].affected_rows, None);
+        assert_eq!(results[1].affected_rows, Some(2));
+        assert_eq!(results[2].affected_rows, None);
+        insta::assert_snapshot!(results[2], @r"
+        +----------------+
+        | str [Utf8View] |
+        +----------------+
+        | bar            |
+        +----------------+
+        | foo            |
+        +----------------+
+        ");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_typed_values() -> datafusion::error::Result<()> {
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec![
+                    "SELECT 1 AS a, NULL AS b, 'NULL' AS c, [1, 2] AS d, named_struct('x', true) AS e"
+                        .to_string(),
+                ],
+                typed_values: true,
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+
+        assert_eq!(
+            result.rows,
+            vec![vec!["1", "", "NULL", "[1, 2]", "{x: true}"]]
+        );
+        assert_eq!(
+            result.values,
+            Some(vec![vec![
+                json!(1),
+                json!(null),
+                json!("NULL"),
+                json!([1, 2]),
+                json!({ "x": true })
+            ]])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_arrow_stream() -> datafusion::error::Result<()> {
+        let bytes = execute_statements_arrow(
+            SqlRequest {
+                stmts: vec!["SELECT MinTemp, RainToday FROM weather LIMIT 3".to_string()],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?;
+
+        let reader = StreamReader::try_new(Cursor::new(bytes), None)?;
+        let schema = reader.schema();
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(schema.field(0).data_type(), &DataType::Float64);
+        assert_eq!(schema.field(1).data_type(), &DataType::Utf8View);
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+        assert_eq!(schema.metadata()["truncated"], "false");
+        assert_eq!(schema.metadata()["total_rows"], "3");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_arrow_stream_truncated() -> datafusion::error::Result<()> {
+        let bytes = execute_statements_arrow(
+            SqlRequest {
+                stmts: vec!["SELECT * FROM generate_series(1, 2000)".to_string()],
+                limit: Some(MAX_ROW_LIMIT),
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?;
+
+        let reader = StreamReader::try_new(Cursor::new(bytes), None)?;
+        let schema = reader.schema();
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2000);
+        assert_eq!(schema.metadata()["truncated"], "false");
+
+        let bytes = execute_statements_arrow(
+            SqlRequest {
+                stmts: vec!["SELECT * FROM generate_series(1, 2000)".to_string()],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?;
+        let reader = StreamReader::try_new(Cursor::new(bytes), None)?;
+        let schema = reader.schema();
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).sum::<usize>(),
+            DEFAULT_ROW_LIMIT
+        );
+        assert_eq!(schema.metadata()["truncated"], "true");
+        assert_eq!(schema.metadata()["limit"], DEFAULT_ROW_LIMIT.to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_text_formats() -> datafusion::error::Result<()> {
+        let res = execute_statements(
+            SqlRequest {
+                stmts: vec![
+                    r#"SELECT * FROM (VALUES (1, 'a,b'), (2, 'say "hi"')) AS t(id, "text")"#
+                        .to_string(),
+                ],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?;
+        let result = res.results.last().unwrap();
+
+        insta::assert_snapshot!(result.to_delimited(','), @r#"
+        id,text
+        1,"a,b"
+        2,"say ""hi"""
+        "#);
+        insta::assert_snapshot!(result.to_delimited('\t'), @r#"
+        id	text
+        1	a,b
+        2	"say ""hi"""
+        "#);
+        assert_eq!(
+            OutputFormat::Csv.page_headers(&res),
+            vec![
+                ("X-Truncated", "false".to_string()),
+                ("X-Total-Rows", "2".to_string()),
+                ("X-Offset", "0".to_string()),
+                ("X-Limit", DEFAULT_ROW_LIMIT.to_string()),
+            ]
+        );
+        assert!(OutputFormat::Json.page_headers(&res).is_empty());
+        insta::assert_snapshot!(OutputFormat::Markdown.render(res), @r#"
+        | id [Int64] | text [Utf8] |
+        |------------|-------------|
+        | 1          | a,b         |
+        | 2          | say "hi"    |
+        "#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_markdown_escaping() -> datafusion::error::Result<()> {
+        let res = execute_statements(
+            SqlRequest {
+                stmts: vec!["SELECT 'a|b' AS \"x|y\", 'line 1\nline 2' AS z".to_string()],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?;
+
+        insta::assert_snapshot!(OutputFormat::Markdown.render(res), @r"
+        | x\|y [Utf8] | z [Utf8]         |
+        |-------------|------------------|
+        | a\|b        | line 1<br>line 2 |
+        ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_format_from_accept() {
+        assert_eq!(
+            OutputFormat::from_accept("text/csv;q=0.9, application/json"),
+            Some(OutputFormat::Csv)
+        );
+        assert_eq!(
+            OutputFormat::from_accept("application/vnd.apache.arrow.stream"),
+            Some(OutputFormat::Arrow)
+        );
+        assert_eq!(OutputFormat::from_accept("*/*"), None);
+    }
+
+    #[tokio::test]
+    async fn test_truncated() -> datafusion::error::Result<()> {
+        let results = execute_statements(
+            SqlRequest {
+                stmts: vec![
+                    "SELECT * FROM lineitem WHERE l_quantity > 0".to_string(),
+                    "SELECT * FROM weather LIMIT 10".to_string(),
+                ],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results;
 
-    let res = match execute_statements(req.stmts, "api/parquet").await {
-        Ok(res) => res,
-        Err(err) => {
-            return throw_error(
-                &err.to_string(),
-                Some(Box::new(err)),
-                StatusCode::BAD_REQUEST,
-            )
-        }
-    };
+        assert!(results[0].truncated);
+        assert_eq!(results[0].total_rows, None);
+        assert_eq!(results[0].limit, DEFAULT_ROW_LIMIT);
+        assert_eq!(results[0].rows.len(), DEFAULT_ROW_LIMIT);
+        assert!(!results[1].truncated);
+        assert_eq!(results[1].total_rows, Some(10));
+        Ok(())
+    }
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header(
-            "Cache-Control",
-            format!(
-                "public, max-age=0, must-revalidate, s-maxage={s_maxage}",
-                s_maxage = 60 * 60
-            ),
+    #[tokio::test]
+    async fn test_row_limit() -> datafusion::error::Result<()> {
+        let path = format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR"));
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec!["SELECT * FROM weather".to_string()],
+                limit: Some(3),
+                ..Default::default()
+            },
+            &path,
         )
-        .header("Content-Type", "application/json")
-        .body(json!(res).to_string().into())?)
-}
+        .await?
+        .results
+        .pop()
+        .unwrap();
+        assert!(result.truncated);
+        assert_eq!(result.rows.len(), 3);
 
-pub fn throw_error(
-    message: &str,
-    error: Option<Error>,
-    status_code: StatusCode,
-) -> Result<Response<Body>, Error> {
-    if let Some(error) = error {
-        eprintln!("error: {error}");
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec!["SELECT 1".to_string()],
+                limit: Some(MAX_ROW_LIMIT),
+                ..Default::default()
+            },
+            &path,
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+        assert_eq!(result.limit, MAX_ROW_LIMIT);
+
+        for limit in [0, MAX_ROW_LIMIT + 1] {
+            let err = execute_statements(
+                SqlRequest {
+                    stmts: vec!["INSERT INTO weather SELECT * FROM weather".to_string()],
+                    limit: Some(limit),
+                    ..Default::default()
+                },
+                &path,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        Ok(())
     }
 
-    Ok(Response::builder()
-        .status(status_code)
-        .header("Content-Type", "application/json")
-        .body(json!({ "message": message }).to_string().into())?)
-}
+    #[tokio::test]
+    async fn test_pagination() -> datafusion::error::Result<()> {
+        let page = |offset| {
+            execute_statements(
+                SqlRequest {
+                    stmts: vec![
+                        "CREATE TABLE nums AS SELECT * FROM generate_series(1, 10) AS t(n)"
+                            .to_string(),
+                        "SELECT n FROM nums ORDER BY n".to_string(),
+                    ],
+                    limit: Some(4),
+                    offset,
+                    ..Default::default()
+                },
+                format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+            )
+        };
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-struct SqlResult {
-    columns: Vec<(String, String)>,
-    rows: Vec<Vec<String>>,
-    logical_plan: String,
-    physical_plan: String,
-}
+        let result = page(4).await?.results.pop().unwrap();
+        assert_eq!(
+            result.rows,
+            vec![vec!["5"], vec!["6"], vec!["7"], vec!["8"]]
+        );
+        assert_eq!(result.offset, 4);
+        assert!(result.truncated);
 
-async fn execute_statements(
-    stmts: Vec<String>,
-    path: impl Display,
-) -> datafusion::error::Result<SqlResult> {
-    let options = FormatOptions::default().with_display_error(true);
-    let cfg = SessionConfig::new().with_information_schema(true);
+        let result = page(8).await?.results.pop().unwrap();
+        assert_eq!(result.rows, vec![vec!["9"], vec!["10"]]);
+        assert!(!result.truncated);
+        assert_eq!(result.total_rows, Some(10));
 
-    let mut builder = SessionStateBuilder::new()
-        .with_default_features()
-        .with_config(cfg)
-        .with_distributed_channel_resolver(CHANNEL_RESOLVER.clone());
-    if stmts.iter().any(|v| v.contains("distributed.")) {
-        builder = builder
-            .with_physical_optimizer_rule(Arc::new(DistributedPhysicalOptimizerRule))
+        let result = page(20).await?.results.pop().unwrap();
+        assert!(result.rows.is_empty());
+        assert_eq!(result.columns, vec![("n".to_string(), "Int64".to_string())]);
+        assert_eq!(result.total_rows, Some(10));
+        Ok(())
     }
-    let ctx = Arc::new(SessionContext::new_with_state(builder.build()));
-    load_parquet_files(path.to_string(), &ctx).await?;
 
-    if stmts.is_empty() {
-        return Ok(SqlResult::default());
-    }
+    #[tokio::test]
+    async fn test_structured_errors() {
+        let run = |stmts: Vec<&str>| {
+            execute_statements(
+                SqlRequest {
+                    stmts: stmts.into_iter().map(String::from).collect(),
+                    ..Default::default()
+                },
+                format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+            )
+        };
 
-    for i in 0..stmts.len() - 1 {
-        ctx.sql(stmts.get(i).unwrap()).await?.collect().await?;
+        let err = run(vec!["SELECT 1", "SELEC 1"]).await.unwrap_err();
+        assert_eq!(err.statement, Some(1));
+        assert_eq!(err.kind(), ErrorKind::SqlParse);
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+
+        let err = run(vec!["SELECT nope FROM weather"]).await.unwrap_err();
+        assert_eq!(err.statement, Some(0));
+        assert_eq!(err.kind(), ErrorKind::Schema);
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            err.span(),
+            Some(SourceRange {
+                start: SourcePosition { line: 1, column: 8 },
+                end: SourcePosition {
+                    line: 1,
+                    column: 12
+                },
+            })
+        );
     }
-    let df = ctx.sql(stmts.last().unwrap()).await?;
-    let logical_plan_str = df.logical_plan().display_indent().to_string();
 
-    let physical_plan = df.create_physical_plan().await?;
+    #[tokio::test]
+    async fn test_timeout() {
+        let err = execute_statements(
+            SqlRequest {
+                stmts: vec![
+                    "SELECT 1".to_string(),
+                    "SELECT count(*) FROM lineitem a, lineitem b, lineitem c".to_string(),
+                ],
+                timeout_ms: Some(200),
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await
+        .unwrap_err();
 
-    let record_batches = execute_stream(physical_plan.clone(), ctx.task_ctx())?
-        .try_collect::<Vec<_>>()
-        .await?;
+        assert_eq!(err.statement, Some(1));
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert_eq!(err.status_code(), StatusCode::GATEWAY_TIMEOUT);
 
-    let mut columns: Vec<(String, String)> = vec![];
-    let mut rows: Vec<Vec<String>> = vec![];
-    for record_batch in record_batches {
-        if columns.is_empty() {
-            columns = record_batch
-                .schema()
-                .fields
-                .iter()
-                .map(|e| (e.name().to_string(), e.data_type().to_string()))
-                .collect()
+        for timeout_ms in [0, 60_000] {
+            let err = execute_statements(
+                SqlRequest {
+                    stmts: vec!["SELECT 1".to_string()],
+                    timeout_ms: Some(timeout_ms),
+                    ..Default::default()
+                },
+                format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
         }
+    }
 
-        let per_column_formatters = record_batch
-            .columns()
-            .iter()
-            .map(|c| ArrayFormatter::try_new(c.as_ref(), &options))
-            .collect::<Result<Vec<_>, ArrowError>>()?;
+    #[tokio::test]
+    async fn test_memory_limit_spills() -> datafusion::error::Result<()> {
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec![r#"
+                    SELECT v, md5(CAST(v AS VARCHAR)) AS h
+                    FROM generate_series(1, 1000000) AS t(v)
+                    ORDER BY h
+                "#
+                .to_string()],
+                memory_limit_mb: Some(8),
+                limit: Some(1),
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
 
-        for i in 0..record_batch.num_rows() {
-            let mut row: Vec<String> = vec![];
-            for formatter in &per_column_formatters {
-                row.push(formatter.value(i).to_string());
-            }
-            rows.push(row);
+        assert!(result.spill_count > 0);
+        assert!(result.spilled_bytes > 0);
+        assert!(result.peak_memory_bytes > 0);
+        assert!(result.peak_memory_bytes <= 8 * 1024 * 1024);
+
+        for memory_limit_mb in [0, 4096] {
+            let err = execute_statements(
+                SqlRequest {
+                    stmts: vec!["SELECT 1".to_string()],
+                    memory_limit_mb: Some(memory_limit_mb),
+                    ..Default::default()
+                },
+                format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
         }
+        Ok(())
     }
-    if rows.len() > MAX_RESULTS {
-        rows.truncate(MAX_RESULTS);
-        rows.push(vec!["...".to_string(); columns.len()]);
+
+    #[tokio::test]
+    async fn test_analyze() -> datafusion::error::Result<()> {
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec!["SELECT RainToday, count(*) FROM weather GROUP BY RainToday".into()],
+                analyze: true,
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+
+        assert!(result.analyzed_plan.unwrap().contains("output_rows="));
+        let metrics = result.metrics.unwrap();
+        assert_eq!(metrics[0].parent, None);
+        assert_eq!(metrics[0].values.get("output_rows"), Some(&2));
+        let scan = metrics.iter().find(|m| m.name == "DataSourceExec").unwrap();
+        assert!(scan.parent.is_some());
+        assert!(scan.values.contains_key("output_rows"));
+        Ok(())
     }
 
-    Ok(SqlResult {
-        columns,
-        rows,
-        logical_plan: logical_plan_str,
-        physical_plan: display_physical_plan(&physical_plan).unwrap_or_else(|err| err.to_string()),
-    })
-}
+    #[tokio::test]
+    async fn test_plan_trees() -> datafusion::error::Result<()> {
+        let result = run_tpch_17(SqlRequest {
+            plan_trees: true,
+            ..Default::default()
+        })
+        .await?;
 
-fn display_physical_plan(physical_plan: &Arc<dyn ExecutionPlan>) -> Result<String, Error> {
-    let physical_plan_str = display_plan_ascii(physical_plan.as_ref(), false);
-    let curr_dir = current_dir()?.display().to_string();
-    let curr_dir = curr_dir.trim_start_matches("/");
-    let physical_plan_str = physical_plan_str.replace(curr_dir, "");
-    Ok(physical_plan_str)
-}
+        let logical = result.logical_plan_tree.unwrap();
+        assert_eq!(logical.name, "Projection");
+        assert_eq!(
+            logical.schema,
+            vec![("avg_yearly".into(), "Float64".into())]
+        );
+        assert_eq!(logical.children[0].name, "Aggregate");
 
-async fn load_parquet_files(base: String, ctx: &SessionContext) -> Result<(), DataFusionError> {
-    let mut futures = vec![];
-    for entry in fs::read_dir(&base)? {
-        let entry_path = entry?.path();
-        let file_name = entry_path.file_name().unwrap().display().to_string();
-        let file_path = format!("{base}/{file_name}");
+        assert!(logical.expressions.is_some());
 
-        let fut = ctx.register_parquet(file_name, file_path, ParquetReadOptions::default());
-        futures.push(fut);
+        fn input_stages<'a>(node: &'a PlanNode, stages: &mut Vec<&'a StageRef>) {
+            stages.extend(node.input_stage.as_ref());
+            node.children.iter().for_each(|c| input_stages(c, stages));
+        }
+        let physical = result.physical_plan_tree.unwrap();
+        assert_eq!(physical.name, "DistributedExec");
+        assert!(physical.expressions.is_none());
+        let mut stages = vec![];
+        input_stages(&physical, &mut stages);
+        stages.sort_by_key(|s| s.stage);
+        assert_eq!(
+            stages.iter().map(|s| s.stage).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        for stage in stages {
+            assert!(!stage.tasks.is_empty());
+            for task in &stage.tasks {
+                let worker = task.worker.as_ref().unwrap();
+                assert!(worker.starts_with("http://worker-"), "{worker}");
+                assert!(task.partitions > 0);
+            }
+        }
+        Ok(())
     }
 
-    for result in futures::future::join_all(futures).await {
-        result?
-    }
+    #[tokio::test]
+    async fn test_diagrams() -> datafusion::error::Result<()> {
+        let run = |stmt: &str, distributed, diagram| {
+            execute_statements(
+                SqlRequest {
+                    stmts: vec![stmt.into()],
+                    distributed: Some(distributed),
+                    files_per_task: Some(1),
+                    diagram: Some(diagram),
+                    ..Default::default()
+                },
+                format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+            )
+        };
 
-    Ok(())
-}
+        let dot = run("SELECT 1 AS a", false, DiagramFormat::Dot)
+            .await?
+            .results
+            .pop()
+            .unwrap()
+            .diagrams
+            .unwrap();
+        assert!(dot.logical_plan.starts_with("digraph {"));
+        assert!(dot
+            .logical_plan
+            .contains("n0 [label=\"Projection: Int64(1) AS a\"];"));
+        assert!(dot.logical_plan.contains("n1 -> n0;"));
+        assert!(dot.physical_plan.contains("n1 -> n0;"));
+        assert_eq!(dot.stages, None);
 
-#[cfg(test)]
-mod tests {
-    use crate::{execute_statements, SqlResult};
+        let mermaid = run(TPCH_17, true, DiagramFormat::Mermaid)
+            .await?
+            .results
+            .pop()
+            .unwrap()
+            .diagrams
+            .unwrap();
+        assert!(mermaid.physical_plan.starts_with("flowchart BT"));
+        let stages = mermaid.stages.unwrap();
+        assert!(stages.contains("n0[\"Stage 0<br>1 tasks x 1 partitions\"]"));
+        assert!(stages.contains("n1 -->|NetworkCoalesceExec| n0"));
+        assert!(stages.contains("n2 -->|NetworkShuffleExec| n0"));
+        Ok(())
+    }
 
     #[tokio::test]
-    async fn test_create_table() -> datafusion::error::Result<()> {
-        let result = execute_statements(
-            vec![
-                "CREATE TABLE book (str text)".to_string(),
-                "INSERT INTO book (str) VALUES ('foo')".to_string(),
-                "SELECT * FROM book".to_string(),
-            ],
-            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
-        )
+    async fn test_analyze_distributed() -> datafusion::error::Result<()> {
+        let result = run_tpch_17(SqlRequest {
+            analyze: true,
+            ..Default::default()
+        })
         .await?;
 
-        insta::assert_snapshot!(result, @r"
-        +----------------+
-        | str [Utf8View] |
-        +----------------+
-        | foo            |
-        +----------------+
-        ");
+        assert_eq!(result.metrics_partial, Some(false));
+        let metrics = result.metrics.unwrap();
+        // Scans that ran on workers, whose metrics were sent back to the coordinator.
+        let remote_scans = metrics
+            .iter()
+            .filter(|m| m.name == "DataSourceExec" && m.stage != 0)
+            .collect::<Vec<_>>();
+        assert!(!remote_scans.is_empty());
+        for scan in remote_scans {
+            assert!(scan.values.get("output_rows").is_some_and(|rows| *rows > 0));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_statements() -> datafusion::error::Result<()> {
+        let run = |sql: &str| {
+            execute_statements(
+                SqlRequest {
+                    sql: Some(sql.to_string()),
+                    ..Default::default()
+                },
+                format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+            )
+        };
+
+        let results = run("SELECT 'a;b' AS v; -- trailing; comment\n\n  SELECT 2;")
+            .await?
+            .results;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].rows, vec![vec!["a;b"]]);
+        assert_eq!(
+            results[1].source,
+            Some(SourceRange {
+                start: SourcePosition { line: 3, column: 3 },
+                end: SourcePosition {
+                    line: 3,
+                    column: 11
+                },
+            })
+        );
+
+        let err = run("SELECT 1;\nSELECT nope\n  FROM weather")
+            .await
+            .unwrap_err();
+        assert_eq!(err.statement, Some(1));
+        assert_eq!(
+            err.span(),
+            Some(SourceRange {
+                start: SourcePosition { line: 2, column: 8 },
+                end: SourcePosition {
+                    line: 2,
+                    column: 12
+                },
+            })
+        );
         Ok(())
     }
 
     #[tokio::test]
     async fn test_parquet() -> datafusion::error::Result<()> {
         let result = execute_statements(
-            vec!["SELECT * FROM weather LIMIT 10".to_string()],
+            SqlRequest {
+                stmts: vec!["SELECT * FROM weather LIMIT 10".to_string()],
+                ..Default::default()
+            },
             format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
         )
-        .await?;
+        .await?
+        .results
+        .pop()
+        .unwrap();
 
         insta::assert_snapshot!(result, @r"
         +-------------------+-------------------+--------------------+-----------------------+---------------------+------------------------+--------------------------+-----------------------+-----------------------+-------------------------+----------------------+---------------------+---------------------+-----------------------+-----------------------+------------------+------------------+-------------------+-------------------+----------------------+-------------------+-------------------------+
@@ -316,13 +3151,7 @@ mod tests {
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_distributed() -> datafusion::error::Result<()> {
-        let result = execute_statements(
-            // TPCH 17
-            vec![
-                "SET distributed.files_per_task = 1;".into(),
-                r#"
+    const TPCH_17: &str = r#"
 select
         sum(l_extendedprice) / 7.0 as avg_yearly
 from
@@ -340,12 +3169,36 @@ where
     where
             l_partkey = p_partkey
 );
-            "#
-                .into(),
-            ],
+            "#;
+
+    /// Runs TPCH 17 distributed, one file per task, with the rest of the options taken from `req`.
+    async fn run_tpch_17(req: SqlRequest) -> Result<SqlResult, SqlError> {
+        let mut response = execute_statements(
+            SqlRequest {
+                stmts: vec![TPCH_17.into()],
+                distributed: Some(true),
+                files_per_task: Some(1),
+                ..req
+            },
             format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
         )
         .await?;
+        Ok(response.results.pop().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_distributed() -> datafusion::error::Result<()> {
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec!["SET distributed.files_per_task = 1;".into(), TPCH_17.into()],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
 
         insta::assert_snapshot!(result.physical_plan, @r"
         ┌───── DistributedExec ── Tasks: t0:[p0] 
@@ -385,19 +3238,365 @@ where
         Ok(())
     }
 
-    impl std::fmt::Display for SqlResult {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            let mut builder = tabled::builder::Builder::new();
-            for (i, (name, typ)) in self.columns.iter().enumerate() {
-                let values = self
-                    .rows
-                    .iter()
-                    .map(|v| v.get(i).unwrap())
-                    .collect::<Vec<_>>();
-                builder.push_column([vec![&format!("{name} [{typ}]")], values].concat())
-            }
-            let table = builder.build();
-            write!(f, "{}", table)
-        }
+    #[tokio::test]
+    async fn test_distributed_stages() -> datafusion::error::Result<()> {
+        let result = run_tpch_17(SqlRequest::default()).await?;
+
+        let stages = result.stages.unwrap();
+        let summary: Vec<_> = stages
+            .iter()
+            .map(|s| (s.stage, s.tasks, s.partitions_per_task))
+            .collect();
+        assert_eq!(summary, vec![(0, 1, 1), (1, 4, 16), (2, 4, 16)]);
+        let exchanges: Vec<_> = stages[0]
+            .inputs
+            .iter()
+            .map(|i| (i.stage, i.exchange.as_str()))
+            .collect();
+        assert_eq!(
+            exchanges,
+            vec![(1, "NetworkCoalesceExec"), (2, "NetworkShuffleExec")]
+        );
+        assert!(stages[2].plan.starts_with("CoalesceBatchesExec"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_workers() -> datafusion::error::Result<()> {
+        let run = |workers| {
+            execute_statements(
+                SqlRequest {
+                    stmts: vec![TPCH_17.into()],
+                    distributed: Some(true),
+                    files_per_task: Some(1),
+                    workers: Some(workers),
+                    ..Default::default()
+                },
+                format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+            )
+        };
+        let max_tasks = |result: SqlResult| {
+            let stages = result.stages.unwrap_or_default();
+            stages.iter().map(|stage| stage.tasks).max().unwrap_or(1)
+        };
+
+        assert_eq!(max_tasks(run(1).await?.results.pop().unwrap()), 1);
+        assert_eq!(max_tasks(run(2).await?.results.pop().unwrap()), 2);
+        assert_eq!(max_tasks(run(4).await?.results.pop().unwrap()), 4);
+
+        let err = run(MAX_WORKERS + 1).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_separate_workers() -> datafusion::error::Result<()> {
+        let urls = CHANNEL_RESOLVER.with_workers(3).get_urls()?;
+        let urls: Vec<_> = urls.iter().map(|url| url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec!["http://worker-0/", "http://worker-1/", "http://worker-2/"]
+        );
+
+        let run = |distributed| {
+            execute_statements(
+                SqlRequest {
+                    stmts: vec![TPCH_17.into()],
+                    distributed: Some(distributed),
+                    files_per_task: Some(1),
+                    workers: Some(3),
+                    ..Default::default()
+                },
+                format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+            )
+        };
+        let single_node = run(false).await?.results.pop().unwrap();
+        let distributed = run(true).await?.results.pop().unwrap();
+        assert_eq!(single_node.rows, distributed.rows);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_worker_restarts() -> datafusion::error::Result<()> {
+        // The last worker, as no other test spreads tasks that far.
+        let worker = CHANNEL_RESOLVER.workers.last().unwrap();
+        assert!(worker.health().await.healthy);
+
+        let mut endpoint = worker.endpoint.lock().unwrap().take().unwrap();
+        endpoint.task.abort();
+        let _ = (&mut endpoint.task).await;
+        *worker.endpoint.lock().unwrap() = Some(endpoint);
+
+        // The channel only notices the connection died when using it, and reconnects on the
+        // next call.
+        let _ = worker.health().await;
+        let health = worker.health().await;
+        assert!(health.healthy, "{health:?}");
+        assert!(health.restarts.unwrap() >= 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_health_checks_used_workers() {
+        let resolver = InMemoryChannelResolver::new(4).with_workers(2);
+        let health = resolver.health().await;
+        assert_eq!(health.len(), 2);
+        assert!(health.iter().all(|worker| worker.healthy));
+        assert!(!resolver.workers[3].started());
+    }
+
+    #[tokio::test]
+    async fn test_network_conditions() -> datafusion::error::Result<()> {
+        let run = |latency_ms, bandwidth_mbps| {
+            run_tpch_17(SqlRequest {
+                network_latency_ms: Some(latency_ms),
+                network_bandwidth_mbps: bandwidth_mbps,
+                ..Default::default()
+            })
+        };
+
+        let ideal = run(0, None).await?;
+        let shaped = run(200, Some(10.0)).await?;
+        assert_eq!(ideal.rows, shaped.rows);
+        assert!(shaped.elapsed_ms >= 200.0);
+
+        let err = run(5000, None).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        let err = run(0, Some(0.0)).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_faults() -> datafusion::error::Result<()> {
+        let run = |fault| {
+            run_tpch_17(SqlRequest {
+                workers: Some(2),
+                faults: vec![
+                    WorkerFault { worker: 0, fault },
+                    WorkerFault { worker: 1, fault },
+                ],
+                timeout_ms: Some(1000),
+                ..Default::default()
+            })
+        };
+
+        let err = run(Fault::Fail).await.unwrap_err();
+        assert!(err.to_string().contains("Simulated worker failure"));
+        let err = run(Fault::Drop).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Simulated worker dropped the stream"));
+        let err = run(Fault::Hang).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+
+        let err = execute_statements(
+            SqlRequest {
+                stmts: vec![TPCH_17.into()],
+                workers: Some(2),
+                faults: vec![WorkerFault {
+                    worker: 2,
+                    fault: Fault::Fail,
+                }],
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let err = run_tpch_17(SqlRequest {
+            workers: Some(2),
+            faults: vec![
+                WorkerFault {
+                    worker: 1,
+                    fault: Fault::Fail,
+                },
+                WorkerFault {
+                    worker: 1,
+                    fault: Fault::Hang,
+                },
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stragglers() -> datafusion::error::Result<()> {
+        let run = |slowdown| {
+            run_tpch_17(SqlRequest {
+                workers: Some(2),
+                stragglers: vec![Straggler {
+                    worker: 1,
+                    slowdown,
+                }],
+                ..Default::default()
+            })
+        };
+
+        let normal = run(1.0).await?;
+        let slow = run(20.0).await?;
+        assert_eq!(normal.rows, slow.rows);
+        assert!(slow.elapsed_ms > normal.elapsed_ms);
+
+        let err = run(0.5).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let err = run_tpch_17(SqlRequest {
+            workers: Some(2),
+            stragglers: vec![
+                Straggler {
+                    worker: 1,
+                    slowdown: 2.0,
+                },
+                Straggler {
+                    worker: 1,
+                    slowdown: 4.0,
+                },
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify() -> datafusion::error::Result<()> {
+        let result = run_tpch_17(SqlRequest {
+            verify: true,
+            ..Default::default()
+        })
+        .await?;
+        let verification = result.verification.unwrap();
+        assert!(verification.matches);
+        assert_eq!(verification.distributed_rows, 1);
+        assert_eq!(verification.single_node_rows, 1);
+        assert!(verification.distributed_plan.contains("NetworkShuffleExec"));
+        assert!(!verification.single_node_plan.contains("NetworkShuffleExec"));
+
+        let results = execute_statements(
+            SqlRequest {
+                stmts: vec![
+                    "CREATE TABLE t AS VALUES (1)".into(),
+                    "SELECT * FROM t".into(),
+                ],
+                distributed: Some(false),
+                verify: true,
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results;
+        assert!(results[0].verification.is_none());
+        assert!(results[1].verification.as_ref().unwrap().matches);
+
+        let results = execute_statements(
+            SqlRequest {
+                stmts: vec![
+                    "CREATE TABLE t (a INT)".into(),
+                    "INSERT INTO t VALUES (1)".into(),
+                ],
+                distributed: Some(true),
+                verify: true,
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results;
+        assert_eq!(results[1].affected_rows, Some(1));
+        assert!(results[1].verification.is_none());
+        assert!(results[1].verification_skipped.is_some());
+
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec![format!("EXPLAIN {TPCH_17}")],
+                distributed: Some(true),
+                verify: true,
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+        assert!(result.verification.is_none());
+        assert!(result.verification_skipped.is_some());
+
+        let result = execute_statements(
+            SqlRequest {
+                stmts: vec![format!(
+                    "SELECT * FROM generate_series(1, {})",
+                    MAX_VERIFY_ROWS + 1
+                )],
+                verify: true,
+                ..Default::default()
+            },
+            format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR")),
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+        assert_eq!(result.rows.len(), DEFAULT_ROW_LIMIT);
+        assert!(result.truncated);
+        assert!(result.verification.is_none());
+        assert!(result.verification_skipped.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_distributed_flag() -> datafusion::error::Result<()> {
+        let path = format!("{}/api/parquet", env!("CARGO_MANIFEST_DIR"));
+        let legacy = execute_statements(
+            SqlRequest {
+                stmts: vec!["SET distributed.files_per_task = 1;".into(), TPCH_17.into()],
+                ..Default::default()
+            },
+            &path,
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+
+        let explicit = execute_statements(
+            SqlRequest {
+                stmts: vec![TPCH_17.into()],
+                distributed: Some(true),
+                files_per_task: Some(1),
+                ..Default::default()
+            },
+            &path,
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+        assert_eq!(legacy.physical_plan, explicit.physical_plan);
+
+        let disabled = execute_statements(
+            SqlRequest {
+                stmts: vec!["SET distributed.files_per_task = 1;".into(), TPCH_17.into()],
+                distributed: Some(false),
+                ..Default::default()
+            },
+            &path,
+        )
+        .await?
+        .results
+        .pop()
+        .unwrap();
+        assert!(!disabled.physical_plan.contains("Stage"));
+        Ok(())
     }
 }
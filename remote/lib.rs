@@ -0,0 +1,145 @@
+//! Resolves the workers running as separate processes. Shared by the `sql` function and the
+//! `worker` binary, which both need to reach the workers listed in `DISTRIBUTED_WORKERS`.
+
+use arrow_flight::flight_service_client::FlightServiceClient;
+use async_trait::async_trait;
+use datafusion::error::DataFusionError;
+use datafusion_distributed::{BoxCloneSyncChannel, ChannelResolver};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use tonic::transport::Endpoint;
+use url::Url;
+
+/// Comma separated URLs of the workers.
+pub const WORKERS_ENV: &str = "DISTRIBUTED_WORKERS";
+/// Path to a file listing the URLs of the workers, one per line. Empty lines and lines starting
+/// with `#` are ignored. Only read if `DISTRIBUTED_WORKERS` is not set.
+pub const WORKERS_FILE_ENV: &str = "DISTRIBUTED_WORKERS_FILE";
+
+/// [ChannelResolver] for workers running as separate processes, reached over TCP.
+#[derive(Clone)]
+pub struct RemoteChannelResolver {
+    urls: Vec<Url>,
+    channels: Arc<Mutex<HashMap<Url, BoxCloneSyncChannel>>>,
+}
+
+impl RemoteChannelResolver {
+    /// Reads the worker URLs from `DISTRIBUTED_WORKERS` or `DISTRIBUTED_WORKERS_FILE`. Returns
+    /// `None` if neither is set.
+    pub fn from_env() -> Result<Option<Self>, DataFusionError> {
+        let urls = env::var(WORKERS_ENV).ok();
+        let file = match (&urls, env::var(WORKERS_FILE_ENV)) {
+            (None, Ok(path)) => Some(fs::read_to_string(&path)?),
+            _ => None,
+        };
+        Ok(
+            parse_worker_urls(urls.as_deref(), file.as_deref())?.map(|urls| Self {
+                urls,
+                channels: Arc::default(),
+            }),
+        )
+    }
+}
+
+/// Parses the value of `DISTRIBUTED_WORKERS`, or else the contents of the file named by
+/// `DISTRIBUTED_WORKERS_FILE`. Returns `None` if neither is given.
+fn parse_worker_urls(
+    urls: Option<&str>,
+    file: Option<&str>,
+) -> Result<Option<Vec<Url>>, DataFusionError> {
+    let urls = match (urls, file) {
+        (Some(urls), _) => urls.split(',').collect::<Vec<_>>(),
+        (None, Some(file)) => file.lines().collect(),
+        (None, None) => return Ok(None),
+    };
+    let urls = urls
+        .into_iter()
+        .map(str::trim)
+        .filter(|url| !url.is_empty() && !url.starts_with('#'))
+        .map(|url| {
+            Url::parse(url).map_err(|err| {
+                DataFusionError::Configuration(format!("Invalid worker URL {url}: {err}"))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if urls.is_empty() {
+        return Err(DataFusionError::Configuration(format!(
+            "No worker URLs found in {WORKERS_ENV} or {WORKERS_FILE_ENV}"
+        )));
+    }
+    Ok(Some(urls))
+}
+
+#[async_trait]
+impl ChannelResolver for RemoteChannelResolver {
+    fn get_urls(&self) -> Result<Vec<Url>, DataFusionError> {
+        Ok(self.urls.clone())
+    }
+
+    async fn get_flight_client_for_url(
+        &self,
+        url: &Url,
+    ) -> Result<FlightServiceClient<BoxCloneSyncChannel>, DataFusionError> {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(channel) = channels.get(url) {
+            return Ok(FlightServiceClient::new(channel.clone()));
+        }
+        // Lazy, so a worker that is down only fails the calls made to it.
+        let channel = Endpoint::from_shared(url.to_string())
+            .map_err(|err| DataFusionError::External(Box::new(err)))?
+            .connect_lazy();
+        let channel = BoxCloneSyncChannel::new(channel);
+        channels.insert(url.clone(), channel.clone());
+        Ok(FlightServiceClient::new(channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_worker_urls;
+
+    fn parse(urls: Option<&str>, file: Option<&str>) -> Option<Vec<String>> {
+        parse_worker_urls(urls, file)
+            .unwrap()
+            .map(|urls| urls.iter().map(|url| url.to_string()).collect())
+    }
+
+    #[test]
+    fn test_parse_worker_urls() {
+        assert_eq!(parse(None, None), None);
+        assert_eq!(
+            parse(Some("http://a:8080, http://b:8080,"), None),
+            Some(vec![
+                "http://a:8080/".to_string(),
+                "http://b:8080/".to_string()
+            ])
+        );
+        assert_eq!(
+            parse(
+                None,
+                Some("# workers\nhttp://a:8080\n\n  http://b:8080  \n")
+            ),
+            Some(vec![
+                "http://a:8080/".to_string(),
+                "http://b:8080/".to_string()
+            ])
+        );
+        // The list takes precedence over the file.
+        assert_eq!(
+            parse(Some("http://a:8080"), Some("http://b:8080")),
+            Some(vec!["http://a:8080/".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_worker_urls_errors() {
+        let err = parse_worker_urls(Some("http://a:8080,not a url"), None).unwrap_err();
+        assert!(err.to_string().contains("Invalid worker URL not a url"));
+        let err = parse_worker_urls(Some(" , "), None).unwrap_err();
+        assert!(err.to_string().contains("No worker URLs found"));
+        let err = parse_worker_urls(None, Some("# none yet\n\n")).unwrap_err();
+        assert!(err.to_string().contains("No worker URLs found"));
+    }
+}
@@ -96,6 +96,16 @@ const SqlResponseVisualizer = React.memo(({ result }: { result: SqlResponse }) =
             </tr>
           ))}
           </tbody>
+          {result.truncated && (
+            <tfoot>
+            <tr>
+              <td colSpan={result.columns.length} className="px-4 py-2 text-text-secondary">
+                Showing the first {result.limit} rows
+                {result.total_rows !== null && ` of ${result.total_rows}`}
+              </td>
+            </tr>
+            </tfoot>
+          )}
         </table>
       </Tabs.Content>
       
@@ -1,7 +1,8 @@
 import React, { useState } from 'react';
 
 export interface SqlRequest {
-  stmts: string[]
+  sql: string
+  distributed?: boolean
 }
 
 export interface SqlResponse {
@@ -9,11 +10,31 @@ export interface SqlResponse {
   rows: Array<Array<string>>,
   logical_plan: string
   physical_plan: string
+  affected_rows: number | null
+  truncated: boolean
+  total_rows: number | null
+  limit: number
+  offset: number
+  elapsed_ms: number
 }
 
-export async function executeStatements (stmts: string[]): Promise<SqlResponse> {
+const EMPTY_RESPONSE: SqlResponse = {
+  columns: [],
+  rows: [],
+  logical_plan: '',
+  physical_plan: '',
+  affected_rows: null,
+  truncated: false,
+  total_rows: 0,
+  limit: 0,
+  offset: 0,
+  elapsed_ms: 0,
+}
+
+export async function executeStatements (sql: string, distributed?: boolean): Promise<SqlResponse> {
   const req: SqlRequest = {
-    stmts,
+    sql,
+    distributed,
   }
   const res = await fetch(
     '/api/main',
@@ -24,8 +45,9 @@ export async function executeStatements (stmts: string[]): Promise<SqlResponse>
     }
   )
   if (res.status === 200) {
-    return await res.json()
-  } else if (res.status === 400) {
+    const { results }: { results: SqlResponse[] } = await res.json()
+    return results[results.length - 1] ?? EMPTY_RESPONSE
+  } else if (res.headers.get('Content-Type') === 'application/json') {
     const { message } = await res.json()
     throw new Error(message)
   } else {
@@ -42,6 +64,7 @@ export type ApiState =
 
 export interface ApiRequest {
   statement: string
+  distributed?: boolean
 }
 
 export function useApi () {
@@ -49,9 +72,7 @@ export function useApi () {
 
   const execute = React.useCallback(async (req: ApiRequest) => {
     setState({ type: 'loading' });
-    const result = await executeStatements(
-      req.statement.split(';').map(_ => _.trim()).filter(_ => _.length > 0),
-    )
+    const result = await executeStatements(req.statement, req.distributed)
       .then((result) => ({ type: 'result' as const, result }))
       .catch((err) => ({ type: 'error' as const, message: err.toString() }));
     setState(result)
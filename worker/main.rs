@@ -0,0 +1,53 @@
+//! Serves a distributed DataFusion worker over TCP, so that the fiddle can run its distributed
+//! plans across processes instead of on simulated in-memory workers.
+//!
+//! ```sh
+//! export DISTRIBUTED_WORKERS=http://127.0.0.1:50051,http://127.0.0.1:50052
+//! cargo run --bin worker -- 127.0.0.1:50051 &
+//! cargo run --bin worker -- 127.0.0.1:50052 &
+//! ```
+//!
+//! Both the workers and the fiddle read the URLs of the workers from `DISTRIBUTED_WORKERS`, or
+//! from the file pointed to by `DISTRIBUTED_WORKERS_FILE`.
+
+use arrow_flight::flight_service_server::FlightServiceServer;
+use datafusion::execution::SessionStateBuilder;
+use datafusion_distributed::{
+    ArrowFlightEndpoint, DistributedExt, DistributedSessionBuilderContext,
+};
+use remote::RemoteChannelResolver;
+use std::env;
+use std::error::Error;
+use std::net::SocketAddr;
+use tonic::transport::Server;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:50051";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let addr: SocketAddr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string())
+        .parse()?;
+    let resolver = RemoteChannelResolver::from_env()?.ok_or(
+        "Set DISTRIBUTED_WORKERS or DISTRIBUTED_WORKERS_FILE so that workers can reach each other",
+    )?;
+
+    let endpoint = ArrowFlightEndpoint::try_new(move |ctx: DistributedSessionBuilderContext| {
+        let resolver = resolver.clone();
+        async move {
+            let builder = SessionStateBuilder::new()
+                .with_default_features()
+                .with_distributed_channel_resolver(resolver)
+                .with_runtime_env(ctx.runtime_env.clone());
+            Ok(builder.build())
+        }
+    })?;
+
+    eprintln!("worker listening on {addr}");
+    Server::builder()
+        .add_service(FlightServiceServer::new(endpoint))
+        .serve(addr)
+        .await?;
+    Ok(())
+}